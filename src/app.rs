@@ -1,6 +1,7 @@
 use anyhow::Result;
 use cgmath::*;
 use std::{
+    path::PathBuf,
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -16,9 +17,24 @@ use winit::{
 
 use crate::{
     camera::CameraWrapper,
+    chunk::{ChunkGenerator, ChunkStreamer},
+    clipmap::{Clipmap, ClipmapSettings, PatchData},
     entity::{Entity, EntityData, EntityWrapper},
-    light::PointLight,
-    texture,
+    erosion::ThermalErosion,
+    frustum::{CullStats, Frustum},
+    glb::{self, SceneNode},
+    gpu_erosion::{self, GpuThermalErosion},
+    heightfield::{HeightFunction, Heightfield},
+    heightmap,
+    light::{DirectionalLight, LightBuffer, PointLight, PointLightData, SpotLight, SpotLightData},
+    model::{self, Model, ModelMaterial},
+    noise::Noise,
+    obj, ply,
+    roam::RoamTerrain,
+    settings::Settings,
+    shadow::{PointShadowMap, ShadowLayer, ShadowMap},
+    stl,
+    texture::{self, TextureSource},
     vertex::{Material, Mesh, Vertex},
 };
 
@@ -27,7 +43,17 @@ struct Pass {
     bind_groups: Vec<wgpu::BindGroup>,
 }
 
+// An imported model part. Its entity follows the cube in AppState::entities,
+// in the same order as AppState::props.
+const FIRST_PROP: usize = 1;
+
+struct Prop {
+    name: String,
+    material: usize,
+}
+
 struct WgpuContext {
+    #[allow(dead_code)]
     instance: wgpu::Instance,
     adapter: wgpu::Adapter,
     device: wgpu::Device,
@@ -42,19 +68,55 @@ struct AppState {
     context: WgpuContext,
     start_time: Instant,
     passes: Vec<Pass>,
+    clipmap_pass: Pass,
+    clipmap: Option<Clipmap>,
+    roam: Option<RoamTerrain>,
+    chunk_pass: Pass,
+    chunks: Option<ChunkStreamer>,
+    prop_pass: Pass,
     camera_wrapper: CameraWrapper,
+    frustum: Frustum,
+    cull_stats: CullStats,
+    shadow_map: ShadowMap,
+    point_shadow_map: PointShadowMap,
+    // Their bind groups start at group 1; group 0 is each shadow layer's camera.
+    shadow_pass: Pass,
+    shadow_clipmap_pass: Pass,
     entities: Vec<EntityWrapper>,
     depth_texture: wgpu::TextureView,
     camera_uniform_buffer: wgpu::Buffer,
-    point_light_uniform_buffer: wgpu::Buffer,
+    point_lights: LightBuffer<PointLightData>,
+    point_light_count: usize,
+    spot_lights: LightBuffer<SpotLightData>,
+    lamps: Vec<SpotLight>,
+    flashlight: bool,
+    sun_uniform_buffer: wgpu::Buffer,
+    // Hour the clock started at, and real seconds per day; 0 stops the clock.
+    time_of_day: f32,
+    day_length: f32,
     entities_data_buffer: wgpu::Buffer,
     material: Material,
+    props: Vec<Prop>,
+    prop_materials: Vec<Material>,
+    heightfield: Heightfield,
+    height_texture: texture::Texture,
+    thermal_erosion: ThermalErosion,
+    gpu_thermal_erosion: GpuThermalErosion,
+    verify_gpu_erosion: bool,
+    export_dir: PathBuf,
+    stl_skirt: f32,
 }
 
 impl AppState {
-    pub async fn new(window: Arc<Window>) -> Result<Self> {
+    pub async fn new(
+        window: Arc<Window>,
+        settings: &Settings,
+        heightfield: Heightfield,
+        models: Vec<Model>,
+    ) -> Result<Self> {
         let size = window.inner_size();
-        let (context, surface) = Self::get_wgpu_context_and_surface(window.clone()).await?;
+        let (context, surface) =
+            Self::get_wgpu_context_and_surface(window.clone(), settings.software).await?;
 
         let surface_caps = surface.get_capabilities(&context.adapter);
         let surface_format = surface_caps
@@ -76,15 +138,23 @@ impl AppState {
 
         // Data loading and stuff now
 
-        let mut plane = Mesh::new(vec![], vec![]);
-        plane.generate_plane(1024);
-        let plane_entity = Entity::new(
-            plane,
-            cgmath::Vector3::zero(),
-            cgmath::Quaternion::from_angle_x(Deg(-90.0)),
-            cgmath::vec3(2.0, 2.0, 2.0),
+        let noise = Noise::new(settings.seed);
+        let thermal_erosion = settings.thermal_erosion();
+        let height_texture = texture::Texture::from_heightfield(
+            &context.device,
+            &context.queue,
+            &heightfield,
+            "heightfield",
+        );
+        let gpu_thermal_erosion = GpuThermalErosion::new(
+            &context.device,
+            &height_texture,
+            &heightfield,
+            &thermal_erosion,
         );
-        let plane_entity_wrapper = EntityWrapper::new(plane_entity, &context.device);
+        // The clipmap draws the terrain straight from the height texture, so
+        // the full plane mesh is only built when an export needs it.
+        let terrain = terrain_entity(Mesh::new(vec![], vec![]), &heightfield);
         let mut cube = Mesh::new(vec![], vec![]);
         cube.generate_cube();
         let cube_entity = Entity::new(
@@ -101,6 +171,11 @@ impl AppState {
             0.1,
             100.0,
         );
+        let frustum = camera_wrapper.get_frustum(
+            surface_config.width as f32 / surface_config.height as f32,
+            0.1,
+            100.0,
+        );
         let camera_uniform_buffer =
             context
                 .device
@@ -135,14 +210,34 @@ impl AppState {
                     resource: camera_uniform_buffer.as_entire_binding(),
                 }],
             });
-        let point_light = PointLight::new((0.0, 2.0, 0.0, 1.0).into(), (1.0, 1.0, 1.0).into());
-        let point_light_uniform_data = point_light.to_uniform_data();
-        let point_light_uniform_buffer =
+        let shadow_map = ShadowMap::new(
+            &context.device,
+            &camera_bind_group_layout,
+            settings.shadows.clone(),
+        );
+        let point_shadow_map = PointShadowMap::new(
+            &context.device,
+            &camera_bind_group_layout,
+            &settings.shadows,
+            settings.point_lights,
+        );
+        let point_lights = LightBuffer::new(&context.device, "point lights", settings.point_lights);
+        let point_light_data = orbiting_lights(settings.point_lights, 0.0)
+            .iter()
+            .map(PointLight::to_data)
+            .collect::<Vec<_>>();
+        point_lights.write(&context.queue, &point_light_data);
+        // One more than the lamps, for the flashlight.
+        let spot_lights =
+            LightBuffer::new(&context.device, "spot lights", settings.lamps.len() + 1);
+        spot_lights.write(&context.queue, &[]);
+        let sun_uniform_data = DirectionalLight::sun(settings.time_of_day).to_uniform_data();
+        let sun_uniform_buffer =
             context
                 .device
                 .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: None,
-                    contents: bytemuck::bytes_of(&point_light_uniform_data),
+                    label: Some("sun"),
+                    contents: bytemuck::bytes_of(&sun_uniform_data),
                     usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
                 });
         let light_bind_group_layout =
@@ -150,26 +245,125 @@ impl AppState {
                 .device
                 .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                     label: Some("camera_bind_group_layout"),
-                    entries: &[wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Uniform,
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
+                    entries: &[
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 0,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
                         },
-                        count: None,
-                    }],
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 1,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Uniform,
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 2,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 3,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Texture {
+                                sample_type: wgpu::TextureSampleType::Depth,
+                                view_dimension: wgpu::TextureViewDimension::D2Array,
+                                multisampled: false,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 4,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 5,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Uniform,
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 6,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Texture {
+                                sample_type: wgpu::TextureSampleType::Depth,
+                                view_dimension: wgpu::TextureViewDimension::D2Array,
+                                multisampled: false,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 7,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Uniform,
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                    ],
                 });
         let light_bind_group = context
             .device
             .create_bind_group(&wgpu::BindGroupDescriptor {
                 label: Some("light_bind_group"),
                 layout: &light_bind_group_layout,
-                entries: &[wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: point_light_uniform_buffer.as_entire_binding(),
-                }],
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: point_lights.buffer().as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: sun_uniform_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: spot_lights.buffer().as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: wgpu::BindingResource::TextureView(shadow_map.view()),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: wgpu::BindingResource::Sampler(shadow_map.sampler()),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 5,
+                        resource: shadow_map.uniform_buf().as_entire_binding(),
+                    },
+                    // Shares the sun's comparison sampler at binding 4.
+                    wgpu::BindGroupEntry {
+                        binding: 6,
+                        resource: wgpu::BindingResource::TextureView(point_shadow_map.view()),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 7,
+                        resource: point_shadow_map.uniform_buf().as_entire_binding(),
+                    },
+                ],
             });
 
         let depth_texture = create_depth_texture(&context.device, &surface_config);
@@ -241,6 +435,33 @@ impl AppState {
             )
         };
 
+        let height_bind_group_layout =
+            context
+                .device
+                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                    label: Some("height_bind_group_layout"),
+                    entries: &[wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::VERTEX,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    }],
+                });
+        let height_bind_group = context
+            .device
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("height_bind_group"),
+                layout: &height_bind_group_layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&height_texture.view),
+                }],
+            });
+
         let cube_pass = {
             let shader = context
                 .device
@@ -301,78 +522,325 @@ impl AppState {
                 bind_groups: vec![camera_bind_group.clone()],
             }
         };
-        let plane_pass = {
-            let render_pipeline = {
-                let shader = context
+        let shaded_pipeline = {
+            let shader = context
+                .device
+                .create_shader_module(wgpu::include_wgsl!("shaders/shader.wgsl"));
+            let render_pipeline_layout =
+                context
                     .device
-                    .create_shader_module(wgpu::include_wgsl!("shaders/shader.wgsl"));
-                let render_pipeline_layout =
-                    context
-                        .device
-                        .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                            label: None,
-                            bind_group_layouts: &[
-                                &camera_bind_group_layout,
-                                &light_bind_group_layout,
-                                &material_bind_group_layout,
-                            ],
-                            push_constant_ranges: &[],
-                        });
+                    .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                        label: None,
+                        bind_group_layouts: &[
+                            &camera_bind_group_layout,
+                            &light_bind_group_layout,
+                            &material_bind_group_layout,
+                            &height_bind_group_layout,
+                        ],
+                        push_constant_ranges: &[],
+                    });
+            let device = &context.device;
+            move |label, vs_entry_point, fs_entry_point, instance_layout| {
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some(label),
+                    layout: Some(&render_pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &shader,
+                        entry_point: Some(vs_entry_point),
+                        compilation_options: wgpu::PipelineCompilationOptions::default(),
+                        buffers: &[Vertex::LAYOUT, instance_layout],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &shader,
+                        entry_point: Some(fs_entry_point),
+                        compilation_options: wgpu::PipelineCompilationOptions::default(),
+                        targets: &[Some(wgpu::ColorTargetState {
+                            format: surface_config.format,
+                            blend: Some(wgpu::BlendState::REPLACE),
+                            write_mask: wgpu::ColorWrites::ALL,
+                        })],
+                    }),
+                    primitive: wgpu::PrimitiveState {
+                        topology: wgpu::PrimitiveTopology::TriangleList,
+                        strip_index_format: None,
+                        front_face: wgpu::FrontFace::Ccw,
+                        cull_mode: Some(wgpu::Face::Back),
+                        polygon_mode: wgpu::PolygonMode::Fill,
+                        unclipped_depth: false,
+                        conservative: false,
+                    },
+                    depth_stencil: Some(wgpu::DepthStencilState {
+                        format: wgpu::TextureFormat::Depth32Float,
+                        depth_write_enabled: true,
+                        depth_compare: wgpu::CompareFunction::Less,
+                        stencil: wgpu::StencilState::default(),
+                        bias: wgpu::DepthBiasState::default(),
+                    }),
+                    multisample: wgpu::MultisampleState::default(),
+                    multiview: None,
+                    cache: None,
+                })
+            }
+        };
+        // Depth only, from the sun and point lights. Groups 1 and 2 are left empty: the vertex
+        // shaders don't use them, and group 1 holds the shadow map itself.
+        let empty_bind_group_layout =
+            context
+                .device
+                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                    label: Some("empty_bind_group_layout"),
+                    entries: &[],
+                });
+        let empty_bind_group = context
+            .device
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("empty_bind_group"),
+                layout: &empty_bind_group_layout,
+                entries: &[],
+            });
+        let shadow_pipeline = {
+            let shader = context
+                .device
+                .create_shader_module(wgpu::include_wgsl!("shaders/shader.wgsl"));
+            let render_pipeline_layout =
                 context
                     .device
-                    .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                        label: Some("render_pipeline"),
-                        layout: Some(&render_pipeline_layout),
-                        vertex: wgpu::VertexState {
-                            module: &shader,
-                            entry_point: Some("vs_main"),
-                            compilation_options: wgpu::PipelineCompilationOptions::default(),
-                            buffers: &[Vertex::LAYOUT, EntityData::LAYOUT],
-                        },
-                        fragment: Some(wgpu::FragmentState {
-                            module: &shader,
-                            entry_point: Some("fs_main"),
-                            compilation_options: wgpu::PipelineCompilationOptions::default(),
-                            targets: &[Some(wgpu::ColorTargetState {
-                                format: surface_config.format,
-                                blend: Some(wgpu::BlendState::REPLACE),
-                                write_mask: wgpu::ColorWrites::ALL,
-                            })],
-                        }),
-                        primitive: wgpu::PrimitiveState {
-                            topology: wgpu::PrimitiveTopology::TriangleList,
-                            strip_index_format: None,
-                            front_face: wgpu::FrontFace::Ccw,
-                            cull_mode: Some(wgpu::Face::Back),
-                            polygon_mode: wgpu::PolygonMode::Fill,
-                            unclipped_depth: false,
-                            conservative: false,
+                    .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                        label: None,
+                        bind_group_layouts: &[
+                            &camera_bind_group_layout,
+                            &empty_bind_group_layout,
+                            &empty_bind_group_layout,
+                            &height_bind_group_layout,
+                        ],
+                        push_constant_ranges: &[],
+                    });
+            let device = &context.device;
+            move |label, vs_entry_point, instance_layout| {
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some(label),
+                    layout: Some(&render_pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &shader,
+                        entry_point: Some(vs_entry_point),
+                        compilation_options: wgpu::PipelineCompilationOptions::default(),
+                        buffers: &[Vertex::LAYOUT, instance_layout],
+                    },
+                    fragment: None,
+                    // Both faces, so thin props and the terrain's back side
+                    // still cast.
+                    primitive: wgpu::PrimitiveState {
+                        topology: wgpu::PrimitiveTopology::TriangleList,
+                        strip_index_format: None,
+                        front_face: wgpu::FrontFace::Ccw,
+                        cull_mode: None,
+                        polygon_mode: wgpu::PolygonMode::Fill,
+                        unclipped_depth: false,
+                        conservative: false,
+                    },
+                    depth_stencil: Some(wgpu::DepthStencilState {
+                        format: wgpu::TextureFormat::Depth32Float,
+                        depth_write_enabled: true,
+                        depth_compare: wgpu::CompareFunction::Less,
+                        stencil: wgpu::StencilState::default(),
+                        bias: wgpu::DepthBiasState {
+                            constant: 2,
+                            slope_scale: 2.0,
+                            clamp: 0.0,
                         },
-                        depth_stencil: Some(wgpu::DepthStencilState {
-                            format: wgpu::TextureFormat::Depth32Float,
-                            depth_write_enabled: true,
-                            depth_compare: wgpu::CompareFunction::Less,
-                            stencil: wgpu::StencilState::default(),
-                            bias: wgpu::DepthBiasState::default(),
-                        }),
-                        multisample: wgpu::MultisampleState::default(),
-                        multiview: None,
-                        cache: None,
-                    })
-            };
-
-            let bind_groups = vec![
+                    }),
+                    multisample: wgpu::MultisampleState::default(),
+                    multiview: None,
+                    cache: None,
+                })
+            }
+        };
+        let shadow_pass = Pass {
+            render_pipeline: shadow_pipeline("shadow_pipeline", "vs_main", EntityData::LAYOUT),
+            bind_groups: vec![
+                empty_bind_group.clone(),
+                empty_bind_group.clone(),
+                height_bind_group.clone(),
+            ],
+        };
+        let shadow_clipmap_pass = Pass {
+            render_pipeline: shadow_pipeline(
+                "shadow_clipmap_pipeline",
+                "vs_clipmap",
+                PatchData::LAYOUT,
+            ),
+            bind_groups: vec![
+                empty_bind_group.clone(),
+                empty_bind_group,
+                height_bind_group.clone(),
+            ],
+        };
+        let clipmap_pass = Pass {
+            render_pipeline: shaded_pipeline(
+                "render_pipeline",
+                "vs_clipmap",
+                "fs_main",
+                PatchData::LAYOUT,
+            ),
+            bind_groups: vec![
+                camera_bind_group.clone(),
+                light_bind_group.clone(),
+                material.bind_group.clone(),
+                height_bind_group.clone(),
+            ],
+        };
+        let chunk_pass = Pass {
+            render_pipeline: shaded_pipeline(
+                "chunk_render_pipeline",
+                "vs_main",
+                "fs_main",
+                EntityData::LAYOUT,
+            ),
+            bind_groups: vec![
+                camera_bind_group.clone(),
+                light_bind_group.clone(),
+                material.bind_group.clone(),
+                height_bind_group.clone(),
+            ],
+        };
+        // Group 2 is swapped for each prop's own material when drawing.
+        let prop_pass = Pass {
+            render_pipeline: shaded_pipeline(
+                "prop_render_pipeline",
+                "vs_main",
+                "fs_model",
+                EntityData::LAYOUT,
+            ),
+            bind_groups: vec![
                 camera_bind_group.clone(),
                 light_bind_group,
                 material.bind_group.clone(),
-            ];
-            Pass {
-                render_pipeline,
-                bind_groups,
-            }
+                height_bind_group,
+            ],
         };
 
-        let entities = vec![cube_entity_wrapper, plane_entity_wrapper];
+        // Streamed chunks are built from the preset on worker threads; heightmaps
+        // only apply to the single plane, which is kept for the exports and G.
+        let chunk_terrain: Option<Arc<dyn HeightFunction + Send + Sync>> =
+            settings.stream.then(|| {
+                settings
+                    .preset
+                    .build(noise.clone(), settings.noise_kind)
+                    .into()
+            });
+        let chunks = chunk_terrain.clone().map(|terrain| {
+            ChunkStreamer::new(
+                ChunkGenerator {
+                    terrain,
+                    seed: settings.seed,
+                    hydraulic_erosion: settings.hydraulic_erosion(),
+                    thermal_erosion: thermal_erosion.clone(),
+                    settings: settings.chunks.clone(),
+                },
+                &context.device,
+            )
+        });
+        // Without tessellation the plane can be triangulated on the CPU instead;
+        // it draws with the chunk pipeline since its heights are in the vertices.
+        let roam = settings.roam.then(|| {
+            RoamTerrain::new(
+                &context.device,
+                &terrain_entity(
+                    terrain_mesh(&heightfield, roam_resolution(&heightfield)),
+                    &heightfield,
+                ),
+                settings.roam_settings.clone(),
+            )
+        });
+        let clipmap = (chunks.is_none() && roam.is_none()).then(|| {
+            Clipmap::new(
+                &context.device,
+                &heightfield,
+                &terrain,
+                ClipmapSettings::default(),
+                point_shadow_map.capacity(),
+            )
+        });
+        let passes = vec![cube_pass];
+
+        let mut prop_entity_wrappers = vec![];
+        let mut props = vec![];
+        let mut prop_materials = vec![];
+        for (placement, model) in settings.models.iter().zip(models) {
+            let first_material = prop_materials.len();
+            for model_material in &model.materials {
+                prop_materials.push(load_prop_material(
+                    &context,
+                    model_material,
+                    &material_bind_group_layout,
+                )?);
+            }
+            let (u, v) = placement.uv;
+            let height = match &chunk_terrain {
+                Some(terrain) => terrain.sample(u, v),
+                None => heightfield.sample(u, v),
+            };
+            let ground = terrain
+                .get_model_matrix()
+                .transform_point(point3(u, v, height));
+            for mut part in model.parts {
+                let material = match part.material {
+                    Some(index) => first_material + index,
+                    None => {
+                        prop_materials.push(load_prop_material(
+                            &context,
+                            &ModelMaterial {
+                                name: part.name.clone(),
+                                diffuse: None,
+                                normal: None,
+                            },
+                            &material_bind_group_layout,
+                        )?);
+                        prop_materials.len() - 1
+                    }
+                };
+                // Props don't move, so the node transform goes into the vertices
+                // and the entity only carries the placement.
+                part.mesh.transform(part.transform);
+                let entity = Entity::new(
+                    part.mesh,
+                    ground.to_vec(),
+                    Quaternion::one(),
+                    vec3(placement.scale, placement.scale, placement.scale),
+                );
+                prop_entity_wrappers.push(EntityWrapper::new(entity, &context.device));
+                props.push(Prop {
+                    name: part.name,
+                    material,
+                });
+            }
+            log::info!("Placed {} at {u}, {v}", placement.path.display());
+        }
+
+        let lamps = settings
+            .lamps
+            .iter()
+            .map(|lamp| {
+                let (u, v) = lamp.uv;
+                let height = match &chunk_terrain {
+                    Some(terrain) => terrain.sample(u, v),
+                    None => heightfield.sample(u, v),
+                };
+                let ground = terrain
+                    .get_model_matrix()
+                    .transform_point(point3(u, v, height));
+                let mut light = SpotLight::new(
+                    ground.to_vec() + vec3(0.0, lamp.height, 0.0),
+                    -Vector3::unit_y(),
+                    vec3(1.0, 0.85, 0.6),
+                );
+                light.intensity = 0.05;
+                light
+            })
+            .collect();
+
+        let mut entities = vec![cube_entity_wrapper];
+        entities.extend(prop_entity_wrappers);
         let entities_data = entities
             .iter()
             .map(|e| e.to_entity_data())
@@ -392,15 +860,43 @@ impl AppState {
             surface_config,
             is_surface_configured: false,
             context,
-            passes: vec![cube_pass, plane_pass],
+            passes,
+            clipmap_pass,
+            clipmap,
+            roam,
+            chunk_pass,
+            chunks,
+            prop_pass,
             start_time: Instant::now(),
             entities,
+            frustum,
+            cull_stats: CullStats::default(),
+            shadow_map,
+            point_shadow_map,
+            shadow_pass,
+            shadow_clipmap_pass,
             camera_wrapper,
             depth_texture,
             camera_uniform_buffer,
-            point_light_uniform_buffer,
+            point_lights,
+            point_light_count: settings.point_lights,
+            spot_lights,
+            lamps,
+            flashlight: false,
+            sun_uniform_buffer,
+            time_of_day: settings.time_of_day,
+            day_length: settings.day_length,
             entities_data_buffer,
             material,
+            props,
+            prop_materials,
+            heightfield,
+            height_texture,
+            thermal_erosion,
+            gpu_thermal_erosion,
+            verify_gpu_erosion: settings.verify_gpu_erosion,
+            export_dir: settings.export_dir.clone(),
+            stl_skirt: settings.stl_skirt,
         })
     }
 
@@ -432,6 +928,31 @@ impl AppState {
                     label: Some("Encoder"),
                 });
 
+        let sun_layers = self.shadow_map.cascades().iter().map(|layer| (layer, None));
+        let point_layers = self
+            .point_shadow_map
+            .lights()
+            .enumerate()
+            .flat_map(|(i, faces)| faces.iter().map(move |face| (face, Some(i))));
+        for (layer, light) in sun_layers.chain(point_layers) {
+            let mut shadow_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("shadow pass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &layer.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: wgpu::StoreOp::Store,
+                    }),
+                    stencil_ops: None,
+                }),
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            self.draw_shadow_casters(&mut shadow_pass, layer, light);
+        }
+
+        let mut stats = CullStats::default();
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: None,
@@ -461,11 +982,14 @@ impl AppState {
                 occlusion_query_set: None,
             });
             for (i, pass) in self.passes.iter().enumerate() {
+                let entity = &self.entities[i];
+                if !stats.count(self.frustum.intersects(&entity.bounds())) {
+                    continue;
+                }
                 render_pass.set_pipeline(&pass.render_pipeline);
                 for (i, bind_group) in pass.bind_groups.iter().enumerate() {
                     render_pass.set_bind_group(i as _, bind_group, &[]);
                 }
-                let entity = &self.entities[i];
 
                 render_pass.set_vertex_buffer(0, entity.mesh_data.vertex_buf.slice(..));
                 render_pass.set_vertex_buffer(
@@ -481,29 +1005,187 @@ impl AppState {
                 );
                 render_pass.draw_indexed(0..entity.mesh_data.index_len, 0, 0..1);
             }
+
+            if let Some(clipmap) = &self.clipmap {
+                stats += clipmap.stats();
+                render_pass.set_pipeline(&self.clipmap_pass.render_pipeline);
+                for (i, bind_group) in self.clipmap_pass.bind_groups.iter().enumerate() {
+                    render_pass.set_bind_group(i as _, bind_group, &[]);
+                }
+                let mesh_data = clipmap.mesh_data();
+                render_pass.set_vertex_buffer(0, mesh_data.vertex_buf.slice(..));
+                render_pass.set_vertex_buffer(1, clipmap.instance_buf().slice(..));
+                render_pass
+                    .set_index_buffer(mesh_data.index_buf.slice(..), wgpu::IndexFormat::Uint32);
+                render_pass.draw_indexed(0..mesh_data.index_len, 0, 0..clipmap.instance_len());
+            }
+
+            if let Some(roam) = &self.roam {
+                render_pass.set_pipeline(&self.chunk_pass.render_pipeline);
+                for (i, bind_group) in self.chunk_pass.bind_groups.iter().enumerate() {
+                    render_pass.set_bind_group(i as _, bind_group, &[]);
+                }
+                render_pass.set_vertex_buffer(0, roam.vertex_buf().slice(..));
+                render_pass.set_vertex_buffer(1, roam.instance_buf().slice(..));
+                render_pass.set_index_buffer(roam.index_buf().slice(..), wgpu::IndexFormat::Uint32);
+                render_pass.draw_indexed(0..roam.index_len(), 0, 0..1);
+            }
+
+            if let Some(chunks) = &self.chunks {
+                render_pass.set_pipeline(&self.chunk_pass.render_pipeline);
+                for (i, bind_group) in self.chunk_pass.bind_groups.iter().enumerate() {
+                    render_pass.set_bind_group(i as _, bind_group, &[]);
+                }
+                for draw in chunks.draws() {
+                    if !stats.count(!draw.occluded && self.frustum.intersects(&draw.bounds)) {
+                        continue;
+                    }
+                    render_pass.set_vertex_buffer(0, draw.vertex_buf.slice(..));
+                    render_pass.set_vertex_buffer(1, draw.instance_buf.slice(..));
+                    render_pass
+                        .set_index_buffer(draw.index_buf.slice(..), wgpu::IndexFormat::Uint32);
+                    for range in draw.ranges {
+                        render_pass.draw_indexed(range, 0, 0..1);
+                    }
+                }
+            }
+
+            render_pass.set_pipeline(&self.prop_pass.render_pipeline);
+            for (i, bind_group) in self.prop_pass.bind_groups.iter().enumerate() {
+                render_pass.set_bind_group(i as _, bind_group, &[]);
+            }
+            for (i, prop) in self.props.iter().enumerate() {
+                let i = FIRST_PROP + i;
+                let entity = &self.entities[i];
+                if !stats.count(self.frustum.intersects(&entity.bounds())) {
+                    continue;
+                }
+                render_pass.set_bind_group(2, &self.prop_materials[prop.material].bind_group, &[]);
+                render_pass.set_vertex_buffer(0, entity.mesh_data.vertex_buf.slice(..));
+                render_pass.set_vertex_buffer(
+                    1,
+                    self.entities_data_buffer.slice(
+                        ((size_of::<EntityData>() * i) as wgpu::BufferAddress)
+                            ..((size_of::<EntityData>() * (i + 1)) as wgpu::BufferAddress),
+                    ),
+                );
+                render_pass.set_index_buffer(
+                    entity.mesh_data.index_buf.slice(..),
+                    wgpu::IndexFormat::Uint32,
+                );
+                render_pass.draw_indexed(0..entity.mesh_data.index_len, 0, 0..1);
+            }
+        }
+
+        if stats != self.cull_stats {
+            log::debug!("drew {} objects, culled {}", stats.drawn, stats.culled);
+            self.cull_stats = stats;
         }
 
         self.context.queue.submit(Some(encoder.finish()));
         output.present();
         Ok(())
     }
+    // Everything that can shade the terrain from a light, culled against the
+    // shadow layer rather than the camera. `light` is the point light the layer
+    // belongs to, or None for the sun.
+    fn draw_shadow_casters(
+        &self,
+        pass: &mut wgpu::RenderPass,
+        layer: &ShadowLayer,
+        light: Option<usize>,
+    ) {
+        // The marker cube sits on the first point light, so it's left out of
+        // that light's own shadow or it would cover everything.
+        let skip = (light == Some(0)).then_some(0);
+        pass.set_pipeline(&self.shadow_pass.render_pipeline);
+        pass.set_bind_group(0, &layer.bind_group, &[]);
+        for (i, bind_group) in self.shadow_pass.bind_groups.iter().enumerate() {
+            pass.set_bind_group(i as u32 + 1, bind_group, &[]);
+        }
+        for (i, entity) in self.entities.iter().enumerate() {
+            if skip == Some(i) || !layer.frustum.intersects(&entity.bounds()) {
+                continue;
+            }
+            pass.set_vertex_buffer(0, entity.mesh_data.vertex_buf.slice(..));
+            pass.set_vertex_buffer(
+                1,
+                self.entities_data_buffer.slice(
+                    ((size_of::<EntityData>() * i) as wgpu::BufferAddress)
+                        ..((size_of::<EntityData>() * (i + 1)) as wgpu::BufferAddress),
+                ),
+            );
+            pass.set_index_buffer(
+                entity.mesh_data.index_buf.slice(..),
+                wgpu::IndexFormat::Uint32,
+            );
+            pass.draw_indexed(0..entity.mesh_data.index_len, 0, 0..1);
+        }
+        if let Some(roam) = &self.roam {
+            pass.set_vertex_buffer(0, roam.vertex_buf().slice(..));
+            pass.set_vertex_buffer(1, roam.instance_buf().slice(..));
+            pass.set_index_buffer(roam.index_buf().slice(..), wgpu::IndexFormat::Uint32);
+            pass.draw_indexed(0..roam.index_len(), 0, 0..1);
+        }
+        if let Some(chunks) = &self.chunks {
+            for draw in chunks.draws() {
+                if !layer.frustum.intersects(&draw.bounds) {
+                    continue;
+                }
+                pass.set_vertex_buffer(0, draw.vertex_buf.slice(..));
+                pass.set_vertex_buffer(1, draw.instance_buf.slice(..));
+                pass.set_index_buffer(draw.index_buf.slice(..), wgpu::IndexFormat::Uint32);
+                for range in draw.ranges {
+                    pass.draw_indexed(range, 0, 0..1);
+                }
+            }
+        }
+
+        if let Some(clipmap) = &self.clipmap {
+            pass.set_pipeline(&self.shadow_clipmap_pass.render_pipeline);
+            for (i, bind_group) in self.shadow_clipmap_pass.bind_groups.iter().enumerate() {
+                pass.set_bind_group(i as u32 + 1, bind_group, &[]);
+            }
+            let mesh_data = clipmap.mesh_data();
+            pass.set_vertex_buffer(0, mesh_data.vertex_buf.slice(..));
+            let (instance_buf, instance_len) = match light {
+                Some(i) => (
+                    clipmap.point_shadow_instance_buf(i),
+                    clipmap.point_shadow_instance_len(i),
+                ),
+                None => (clipmap.shadow_instance_buf(), clipmap.shadow_instance_len()),
+            };
+            pass.set_vertex_buffer(1, instance_buf.slice(..));
+            pass.set_index_buffer(mesh_data.index_buf.slice(..), wgpu::IndexFormat::Uint32);
+            pass.draw_indexed(0..mesh_data.index_len, 0, 0..instance_len);
+        }
+    }
+
     pub fn update(&mut self, dt: Duration) {
         let time_since_start = Instant::now().duration_since(self.start_time);
-        let (s, c) = cgmath::Angle::sin_cos(Deg(100.0 * time_since_start.as_secs_f32()));
 
-        let point_light = PointLight::new(
-            (s * 0.5 + 1.5, 0.2, c * 0.5 - 1.5, 1.0).into(),
-            (0.3, 0.3, 0.3).into(),
-        );
+        let point_lights = orbiting_lights(self.point_light_count, time_since_start.as_secs_f32());
+        let point_light_data = point_lights
+            .iter()
+            .map(PointLight::to_data)
+            .collect::<Vec<_>>();
+        self.point_lights
+            .write(&self.context.queue, &point_light_data);
 
-        let point_light_uniform_data = point_light.to_uniform_data();
+        let hour = if self.day_length > 0.0 {
+            self.time_of_day + time_since_start.as_secs_f32() / self.day_length * 24.0
+        } else {
+            self.time_of_day
+        };
+        let sun = DirectionalLight::sun(hour.rem_euclid(24.0));
+        let sun_uniform_data = sun.to_uniform_data();
         self.context.queue.write_buffer(
-            &self.point_light_uniform_buffer,
+            &self.sun_uniform_buffer,
             0,
-            &bytemuck::bytes_of(&point_light_uniform_data),
+            bytemuck::bytes_of(&sun_uniform_data),
         );
 
-        self.entities[0].update_entity_position(point_light.position.xyz());
+        self.entities[0].update_entity_position(point_lights[0].position);
         let entities_data = self
             .entities
             .iter()
@@ -512,10 +1194,76 @@ impl AppState {
         self.context.queue.write_buffer(
             &self.entities_data_buffer,
             0,
-            &bytemuck::cast_slice(&entities_data),
+            bytemuck::cast_slice(&entities_data),
         );
 
         self.camera_wrapper.update(dt);
+        let mut spot_light_data = self
+            .lamps
+            .iter()
+            .map(SpotLight::to_data)
+            .collect::<Vec<_>>();
+        if self.flashlight {
+            let mut flashlight = SpotLight::new(
+                self.camera_wrapper.position(),
+                self.camera_wrapper.front(),
+                vec3(1.0, 1.0, 0.9),
+            );
+            flashlight.intensity = 0.5;
+            spot_light_data.push(flashlight.to_data());
+        }
+        self.spot_lights
+            .write(&self.context.queue, &spot_light_data);
+        self.frustum = self.camera_wrapper.get_frustum(
+            self.surface_config.width as f32 / self.surface_config.height as f32,
+            0.1,
+            100.0,
+        );
+        self.shadow_map.update(
+            &self.context.queue,
+            &self.camera_wrapper,
+            self.surface_config.width as f32 / self.surface_config.height as f32,
+            0.1,
+            sun.direction,
+        );
+        self.point_shadow_map.update(
+            &self.context.queue,
+            &point_lights,
+            self.camera_wrapper.position(),
+        );
+        if let Some(clipmap) = &mut self.clipmap {
+            clipmap.update(
+                &self.context.queue,
+                self.camera_wrapper.position(),
+                &self.frustum,
+            );
+            clipmap.update_shadow_casters(
+                &self.context.queue,
+                self.camera_wrapper.position(),
+                self.shadow_map.casters(),
+            );
+            clipmap.update_point_shadow_casters(
+                &self.context.queue,
+                self.camera_wrapper.position(),
+                &point_lights,
+            );
+        }
+        if let Some(chunks) = &mut self.chunks {
+            chunks.update(&self.context.device, self.camera_wrapper.position());
+        }
+        if let Some(roam) = &mut self.roam {
+            roam.update(
+                &self.context.device,
+                &self.context.queue,
+                self.camera_wrapper.position(),
+                self.camera_wrapper.get_projection_matrix(
+                    self.surface_config.width as f32 / self.surface_config.height as f32,
+                    0.1,
+                    100.0,
+                ),
+                self.surface_config.height as f32,
+            );
+        }
         let camera_uniform_data = self.camera_wrapper.get_camera_uniform_data(
             self.surface_config.width as f32 / self.surface_config.height as f32,
             0.1,
@@ -527,15 +1275,133 @@ impl AppState {
             bytemuck::bytes_of(&camera_uniform_data),
         );
     }
+    fn erode_on_gpu(&mut self) -> Result<()> {
+        let reference = self.verify_gpu_erosion.then(|| {
+            let mut reference = self.heightfield.clone();
+            self.thermal_erosion.apply(&mut reference);
+            reference
+        });
+        self.gpu_thermal_erosion.run(
+            &self.context.device,
+            &self.context.queue,
+            &self.height_texture,
+        );
+        gpu_erosion::read_back(
+            &self.context.device,
+            &self.context.queue,
+            &self.height_texture,
+            &mut self.heightfield,
+        )?;
+        if let Some(reference) = reference {
+            let max_error = reference
+                .data()
+                .iter()
+                .zip(self.heightfield.data())
+                .map(|(a, b)| (a - b).abs())
+                .fold(0.0, f32::max);
+            log::info!("GPU thermal erosion differs from the CPU reference by at most {max_error}");
+        }
+        if let Some(clipmap) = &mut self.clipmap {
+            clipmap.set_heights(&self.heightfield);
+        }
+        if let Some(roam) = &mut self.roam {
+            let mesh = terrain_mesh(&self.heightfield, roam_resolution(&self.heightfield));
+            roam.set_terrain(
+                &self.context.device,
+                &terrain_entity(mesh, &self.heightfield),
+            );
+        }
+        Ok(())
+    }
+
+    // The terrain as a mesh at up to 1024 quads a side, for the exports.
+    fn terrain(&self) -> Entity {
+        let resolution = self.heightfield.width().max(self.heightfield.height()) - 1;
+        let mesh = terrain_mesh(&self.heightfield, resolution.min(1024));
+        terrain_entity(mesh, &self.heightfield)
+    }
+
+    fn export_heightmap(&self) -> Result<()> {
+        std::fs::create_dir_all(&self.export_dir)?;
+        for name in ["terrain.png", "terrain.r16", "terrain.r32", "terrain.exr"] {
+            let path = self.export_dir.join(name);
+            let (offset, scale) = heightmap::save(&self.heightfield, &path)?;
+            log::info!(
+                "Wrote {} (import with --height-scale {scale} --height-offset {offset})",
+                path.display()
+            );
+        }
+        let path = self.export_dir.join("terrain_normal.png");
+        heightmap::save_normal_map(&self.heightfield, &path)?;
+        log::info!("Wrote {}", path.display());
+        Ok(())
+    }
+
+    fn export_obj(&self) -> Result<()> {
+        std::fs::create_dir_all(&self.export_dir)?;
+        let path = self.export_dir.join("terrain.obj");
+        obj::save(&path, "terrain", &self.terrain(), Some(&self.material))?;
+        log::info!("Wrote {}", path.display());
+        Ok(())
+    }
+
+    fn export_print(&self) -> Result<()> {
+        std::fs::create_dir_all(&self.export_dir)?;
+        let terrain = self.terrain();
+        let path = self.export_dir.join("terrain.stl");
+        stl::save(
+            &path,
+            &terrain,
+            (self.stl_skirt > 0.0).then_some(self.stl_skirt),
+        )?;
+        log::info!("Wrote {}", path.display());
+        let path = self.export_dir.join("terrain.ply");
+        ply::save(&path, &terrain)?;
+        log::info!("Wrote {}", path.display());
+        Ok(())
+    }
+
+    fn export_glb(&self) -> Result<()> {
+        std::fs::create_dir_all(&self.export_dir)?;
+        let path = self.export_dir.join("scene.glb");
+        let terrain = self.terrain();
+        let mut nodes = vec![
+            SceneNode {
+                name: "light",
+                entity: &self.entities[0].entity,
+                material: None,
+            },
+            SceneNode {
+                name: "terrain",
+                entity: &terrain,
+                material: Some(&self.material),
+            },
+        ];
+        nodes.extend(
+            self.props
+                .iter()
+                .zip(&self.entities[FIRST_PROP..])
+                .map(|(prop, wrapper)| SceneNode {
+                    name: &prop.name,
+                    entity: &wrapper.entity,
+                    material: Some(&self.prop_materials[prop.material]),
+                }),
+        );
+        glb::save(&path, &nodes)?;
+        log::info!("Wrote {}", path.display());
+        Ok(())
+    }
+
     async fn get_wgpu_context_and_surface(
         window: Arc<Window>,
+        force_fallback_adapter: bool,
     ) -> Result<(WgpuContext, wgpu::Surface<'static>)> {
         let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
         let surface = instance.create_surface(window.clone())?;
         let adapter = instance
             .request_adapter(&wgpu::RequestAdapterOptionsBase {
                 power_preference: Default::default(),
-                force_fallback_adapter: false,
+                force_fallback_adapter,
                 compatible_surface: Some(&surface),
             })
             .await?;
@@ -571,28 +1437,110 @@ impl AppState {
     }
 }
 
+// The first light orbits where the single light always has, with the cube
+// following it; any others share its orbit, evenly spaced, in other colors.
+fn orbiting_lights(count: usize, seconds: f32) -> Vec<PointLight> {
+    const COLORS: [[f32; 3]; 4] = [
+        [0.3, 0.3, 0.3],
+        [0.4, 0.25, 0.1],
+        [0.1, 0.2, 0.4],
+        [0.15, 0.35, 0.15],
+    ];
+    (0..count)
+        .map(|i| {
+            let phase = 360.0 * i as f32 / count as f32;
+            let (s, c) = Angle::sin_cos(Deg(100.0 * seconds + phase));
+            PointLight::new(
+                vec3(s * 0.5 + 1.5, 0.2, c * 0.5 - 1.5),
+                COLORS[i % COLORS.len()].into(),
+            )
+        })
+        .collect()
+}
+
+// The ROAM bintree needs a power of two grid; other sizes are resampled.
+fn roam_resolution(heightfield: &Heightfield) -> u32 {
+    let resolution = heightfield.width().max(heightfield.height()) - 1;
+    resolution.next_power_of_two().min(1024)
+}
+
+// Lays the heightfield's uv square flat, two world units wide and stretched
+// along z when the heightfield isn't square.
+fn terrain_entity(mesh: Mesh, heightfield: &Heightfield) -> Entity {
+    let aspect = (heightfield.height() - 1) as f32 / (heightfield.width() - 1) as f32;
+    Entity::new(
+        mesh,
+        cgmath::Vector3::zero(),
+        cgmath::Quaternion::from_angle_x(Deg(-90.0)),
+        cgmath::vec3(2.0, 2.0, 2.0 * aspect),
+    )
+}
+
+fn terrain_mesh(heightfield: &Heightfield, resolution: u32) -> Mesh {
+    let mut plane = Mesh::new(vec![], vec![]);
+    plane.generate_plane(resolution, heightfield);
+    plane
+}
+
+// Textures the model doesn't have, or that can't be decoded, fall back to
+// plain white, which leaves the vertex colors as they are.
+fn load_prop_material(
+    context: &WgpuContext,
+    model_material: &ModelMaterial,
+    layout: &wgpu::BindGroupLayout,
+) -> Result<Material> {
+    let white = |format| {
+        texture::Texture::from_color(
+            &context.device,
+            &context.queue,
+            [255, 255, 255, 255],
+            "white",
+            format,
+        )
+    };
+    let load = |source: Option<&TextureSource>, format| match source {
+        Some(source) => texture::Texture::from_bytes(
+            &context.device,
+            &context.queue,
+            &source.bytes,
+            &source.name,
+            format,
+        )
+        .or_else(|e| {
+            log::warn!("Unable to load {}, using plain white: {e}", source.name);
+            white(format)
+        }),
+        None => white(format),
+    };
+    let diffuse_texture = load(
+        model_material.diffuse.as_ref(),
+        wgpu::TextureFormat::Rgba8UnormSrgb,
+    )?;
+    let normal_texture = load(
+        model_material.normal.as_ref(),
+        wgpu::TextureFormat::Rgba8Unorm,
+    )?;
+    Ok(Material::new(
+        &context.device,
+        &model_material.name,
+        diffuse_texture,
+        normal_texture,
+        layout,
+    ))
+}
+
 fn create_depth_texture(
     device: &wgpu::Device,
     config: &wgpu::SurfaceConfiguration,
 ) -> wgpu::TextureView {
-    let depth_texture = device.create_texture(&wgpu::wgt::TextureDescriptor {
-        label: Some("Depth Texture"),
-        size: wgpu::Extent3d {
-            width: config.width,
-            height: config.height,
-            depth_or_array_layers: 1,
-        },
-        mip_level_count: 1,
-        sample_count: 1,
-        dimension: wgpu::TextureDimension::D2,
-        format: wgpu::TextureFormat::Depth32Float,
-        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
-        view_formats: &[],
-    });
-    depth_texture.create_view(&wgpu::wgt::TextureViewDescriptor::default())
+    texture::create_depth_texture(device, "Depth Texture", config.width, config.height, 1)
+        .create_view(&wgpu::wgt::TextureViewDescriptor::default())
 }
 
 struct App {
+    settings: Settings,
+    heightfield: Heightfield,
+    models: Vec<Model>,
     state: Option<AppState>,
     last_time: Instant,
 }
@@ -610,7 +1558,13 @@ impl ApplicationHandler<AppState> for App {
             .unwrap();
         window.set_cursor_visible(false);
 
-        let state = pollster::block_on(AppState::new(window)).unwrap();
+        let state = pollster::block_on(AppState::new(
+            window,
+            &self.settings,
+            self.heightfield.clone(),
+            self.models.clone(),
+        ))
+        .unwrap();
         self.state = Some(state);
     }
 
@@ -650,15 +1604,45 @@ impl ApplicationHandler<AppState> for App {
                     KeyEvent {
                         physical_key: PhysicalKey::Code(code),
                         state: key_state,
+                        repeat,
                         ..
                     },
                 ..
             } => {
                 state.handle_keyboard(code, key_state);
+                // Key repeat would flip the flashlight or erode again while F or G is held.
                 match (code, key_state.is_pressed()) {
                     (KeyCode::Escape, true) => {
                         event_loop.exit();
                     }
+                    (KeyCode::KeyF, true) if !repeat => {
+                        state.flashlight = !state.flashlight;
+                    }
+                    (KeyCode::KeyG, true) if !repeat => {
+                        if let Err(e) = state.erode_on_gpu() {
+                            log::error!("Unable to run GPU erosion {}", e);
+                        }
+                    }
+                    (KeyCode::F2, true) => {
+                        if let Err(e) = state.export_heightmap() {
+                            log::error!("Unable to export heightmap {}", e);
+                        }
+                    }
+                    (KeyCode::F3, true) => {
+                        if let Err(e) = state.export_obj() {
+                            log::error!("Unable to export OBJ {}", e);
+                        }
+                    }
+                    (KeyCode::F4, true) => {
+                        if let Err(e) = state.export_glb() {
+                            log::error!("Unable to export glTF {}", e);
+                        }
+                    }
+                    (KeyCode::F5, true) => {
+                        if let Err(e) = state.export_print() {
+                            log::error!("Unable to export STL/PLY {}", e);
+                        }
+                    }
                     _ => {}
                 }
             }
@@ -670,36 +1654,67 @@ impl ApplicationHandler<AppState> for App {
     }
     fn device_event(
         &mut self,
-        event_loop: &winit::event_loop::ActiveEventLoop,
-        device_id: winit::event::DeviceId,
+        _event_loop: &winit::event_loop::ActiveEventLoop,
+        _device_id: winit::event::DeviceId,
         event: winit::event::DeviceEvent,
     ) {
         let state = match &mut self.state {
             Some(s) => s,
             None => return,
         };
-        match event {
-            DeviceEvent::MouseMotion { delta: (dx, dy) } => {
-                state.handle_mouse_motion(dx, dy);
-            }
-            _ => {}
+        if let DeviceEvent::MouseMotion { delta: (dx, dy) } = event {
+            state.handle_mouse_motion(dx, dy);
         }
     }
 }
 
 impl App {
-    fn init() -> Self {
+    fn init(settings: Settings, heightfield: Heightfield, models: Vec<Model>) -> Self {
         Self {
+            settings,
+            heightfield,
+            models,
             state: None,
             last_time: Instant::now(),
         }
     }
 }
 
+// Generates or imports the terrain. This runs before the window opens so
+// that a bad heightmap is reported like any other argument error.
+fn load_heightfield(settings: &Settings) -> Result<Heightfield> {
+    let mut heightfield = match &settings.heightmap {
+        Some(path) => settings.heightmap_import.load(path)?,
+        None => {
+            let terrain = settings
+                .preset
+                .build(Noise::new(settings.seed), settings.noise_kind);
+            Heightfield::from_fn(1025, 1025, terrain.as_ref())
+        }
+    };
+    // Imported heightmaps are usually finished terrain, so they are only
+    // eroded when asked to be.
+    if settings.heightmap.is_none() || settings.erode_heightmap {
+        settings
+            .hydraulic_erosion()
+            .apply(&mut heightfield, settings.seed);
+        settings.thermal_erosion().apply(&mut heightfield);
+    }
+    Ok(heightfield)
+}
+
 pub fn run() -> Result<()> {
+    let settings = Settings::from_args()?;
+    let heightfield = load_heightfield(&settings)?;
+    // Models are read up front for the same reason.
+    let models = settings
+        .models
+        .iter()
+        .map(|placement| model::load(&placement.path))
+        .collect::<Result<Vec<_>>>()?;
     let event_loop = EventLoop::with_user_event().build()?;
     event_loop.set_control_flow(ControlFlow::Poll);
-    let mut app = App::init();
+    let mut app = App::init(settings, heightfield, models);
     event_loop.run_app(&mut app)?;
     Ok(())
 }
@@ -6,6 +6,8 @@ use std::{
 use bytemuck::{Pod, Zeroable};
 use cgmath::{num_traits::clamp, *};
 use winit::{event::ElementState, keyboard::KeyCode};
+
+use crate::frustum::Frustum;
 pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::from_cols(
     cgmath::Vector4::new(1.0, 0.0, 0.0, 0.0),
     cgmath::Vector4::new(0.0, 1.0, 0.0, 0.0),
@@ -134,20 +136,12 @@ impl Default for CameraController {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct CameraWrapper {
     pub camera: Camera,
     camera_controller: CameraController,
 }
 
-impl Default for CameraWrapper {
-    fn default() -> Self {
-        Self {
-            camera: Default::default(),
-            camera_controller: Default::default(),
-        }
-    }
-}
 impl CameraWrapper {
     pub fn update(&mut self, dt: Duration) {
         self.camera_controller.update_camera(&mut self.camera, dt);
@@ -159,6 +153,12 @@ impl CameraWrapper {
     pub fn handle_keyboard(&mut self, key: KeyCode, state: ElementState) {
         self.camera_controller.handle_keyboard(key, state);
     }
+    pub fn position(&self) -> Vector3<f32> {
+        self.camera.position
+    }
+    pub fn front(&self) -> Vector3<f32> {
+        self.camera.front
+    }
     pub fn get_view_matrix(&self) -> Matrix4<f32> {
         self.camera.get_view_matrix()
     }
@@ -166,6 +166,10 @@ impl CameraWrapper {
         self.camera.get_projection_matrix(aspect, znear, zfar)
     }
 
+    pub fn get_frustum(&self, aspect: f32, znear: f32, zfar: f32) -> Frustum {
+        Frustum::new(self.get_projection_matrix(aspect, znear, zfar) * self.get_view_matrix())
+    }
+
     pub fn get_camera_uniform_data(&self, aspect: f32, znear: f32, zfar: f32) -> CameraUniformData {
         let view = self.get_view_matrix().into();
         let proj = self.get_projection_matrix(aspect, znear, zfar).into();
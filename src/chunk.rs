@@ -0,0 +1,501 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use cgmath::*;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    entity::Entity,
+    erosion::{HydraulicErosion, ThermalErosion},
+    frustum::Aabb,
+    heightfield::{HeightFunction, Heightfield},
+    horizon::Horizon,
+    lod::LodSettings,
+    vertex::{Mesh, PlaneEdge, PlaneStitching},
+    worker::ChunkWorkers,
+};
+
+// Chunks are laid out in the terrain's uv space, the same space the single
+// plane covers from 0 to 1, so chunk (0, 0) starts where the plane does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChunkCoord {
+    pub x: i32,
+    pub y: i32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChunkSettings {
+    pub size: f32,
+    pub resolution: u32,
+    pub load_radius: i32,
+    pub unload_radius: i32,
+    pub max_uploads_per_frame: usize,
+    pub lod: LodSettings,
+    // Skip chunks hidden behind nearer ones.
+    pub occlusion_culling: bool,
+}
+
+impl Default for ChunkSettings {
+    fn default() -> Self {
+        Self {
+            size: 0.25,
+            resolution: 64,
+            load_radius: 6,
+            unload_radius: 8,
+            max_uploads_per_frame: 4,
+            lod: LodSettings::default(),
+            occlusion_culling: true,
+        }
+    }
+}
+
+// World units per terrain uv unit, matching the single plane's entity scale.
+pub const WORLD_SCALE: f32 = 2.0;
+
+// Occluder cells along each side of a chunk.
+const OCCLUDER_CELLS: u32 = 4;
+
+// One vertex buffer per LOD level, finest first, all built from the same
+// eroded heights. Index buffers are shared by every chunk on the same level,
+// see ChunkStreamer::draws.
+pub struct Chunk {
+    pub vertex_bufs: Vec<wgpu::Buffer>,
+    // World space, from the full resolution mesh.
+    pub bounds: Aabb,
+    // World space boxes over a coarse grid of the chunk, each as low as the
+    // ground anywhere inside it, for hiding the chunks behind.
+    pub occluders: Vec<Aabb>,
+    pub level: usize,
+    pub instance_buf: wgpu::Buffer,
+}
+
+// What it takes to draw one chunk: each index range is drawn with the same
+// vertex and instance buffers.
+pub struct ChunkDraw<'a> {
+    pub bounds: Aabb,
+    // Hidden behind nearer terrain, though it may still cast shadows.
+    pub occluded: bool,
+    pub vertex_buf: &'a wgpu::Buffer,
+    pub instance_buf: &'a wgpu::Buffer,
+    pub index_buf: &'a wgpu::Buffer,
+    pub ranges: [Range<u32>; 5],
+}
+
+struct LevelIndices {
+    index_buf: wgpu::Buffer,
+    stitching: PlaneStitching,
+}
+
+// A square of the terrain stretched to the unit square that Heightfield and
+// Mesh::generate_plane expect. Heights are divided by the span so slopes, and
+// with them the thermal talus angle, come out the same as on the single plane.
+struct ChunkTerrain<'a> {
+    terrain: &'a (dyn HeightFunction + Send + Sync),
+    origin: Vector2<f32>,
+    span: f32,
+}
+
+impl HeightFunction for ChunkTerrain<'_> {
+    fn sample(&self, u: f32, v: f32) -> f32 {
+        self.terrain
+            .sample(self.origin.x + u * self.span, self.origin.y + v * self.span)
+            / self.span
+    }
+}
+
+// Everything a worker needs to build a chunk's mesh without the GPU.
+pub struct ChunkGenerator {
+    pub terrain: Arc<dyn HeightFunction + Send + Sync>,
+    pub seed: u64,
+    pub hydraulic_erosion: HydraulicErosion,
+    pub thermal_erosion: ThermalErosion,
+    pub settings: ChunkSettings,
+}
+
+impl ChunkGenerator {
+    pub fn origin(&self, coord: ChunkCoord) -> Vector2<f32> {
+        vec2(coord.x as f32, coord.y as f32) * self.settings.size
+    }
+
+    // Distance in terrain uv from a point to the nearest edge of a chunk, zero
+    // inside it. Measured flat so neighbours on a slope agree on their level.
+    pub fn distance(&self, coord: ChunkCoord, uv: Vector2<f32>) -> f32 {
+        let min = self.origin(coord);
+        let max = min + vec2(1.0, 1.0) * self.settings.size;
+        let nearest = vec2(uv.x.clamp(min.x, max.x), uv.y.clamp(min.y, max.y));
+        (uv - nearest).magnitude()
+    }
+
+    // Erosion runs over the chunk plus a margin and is faded out towards the
+    // chunk's edges, so neighbours meet on the uneroded terrain without seams.
+    // Returns one mesh per LOD level, finest first, or None as soon as the job
+    // is cancelled.
+    pub fn generate(&self, coord: ChunkCoord, cancelled: &AtomicBool) -> Option<Vec<Mesh>> {
+        let size = self.settings.size;
+        let resolution = self.settings.resolution;
+        let margin = resolution / 4;
+        let cells = resolution + 2 * margin;
+        let span = size * cells as f32 / resolution as f32;
+        let origin = self.origin(coord);
+        let terrain = ChunkTerrain {
+            terrain: self.terrain.as_ref(),
+            origin: origin - vec2(1.0, 1.0) * (span - size) * 0.5,
+            span,
+        };
+        let mut heightfield = Heightfield::from_fn(cells + 1, cells + 1, &terrain);
+        let base = heightfield.clone();
+
+        if cancelled.load(Ordering::Relaxed) {
+            return None;
+        }
+        let hydraulic_erosion = HydraulicErosion {
+            droplets: (self.hydraulic_erosion.droplets as u64 * (cells * cells) as u64
+                / (1024 * 1024)) as u32,
+            ..self.hydraulic_erosion.clone()
+        };
+        let seed = self.seed
+            ^ (coord.x as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+            ^ (coord.y as u64).wrapping_mul(0xc2b2_ae3d_27d4_eb4f);
+        hydraulic_erosion.apply(&mut heightfield, seed);
+        if cancelled.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.thermal_erosion.apply(&mut heightfield);
+        if cancelled.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        let fade_cells = (resolution / 8).max(1) as f32;
+        let mut data = Vec::with_capacity(((resolution + 1) * (resolution + 1)) as usize);
+        for y in 0..=resolution {
+            for x in 0..=resolution {
+                let edge = x.min(y).min(resolution - x).min(resolution - y) as f32;
+                let t = (edge / fade_cells).min(1.0);
+                let weight = t * t * (3.0 - 2.0 * t);
+                let (hx, hy) = ((x + margin) as i32, (y + margin) as i32);
+                let h = base.get(hx, hy) + (heightfield.get(hx, hy) - base.get(hx, hy)) * weight;
+                data.push(h * span / size);
+            }
+        }
+        let heightfield = Heightfield::from_data(resolution + 1, resolution + 1, data);
+
+        // Coarser levels skip grid points rather than re-running erosion at a
+        // lower resolution, so every level shows the same valleys.
+        let levels = self.settings.lod.levels(resolution) as usize;
+        let meshes = (0..levels)
+            .map(|level| {
+                let mut mesh = Mesh::new(vec![], vec![]);
+                mesh.generate_plane(
+                    self.settings.lod.resolution(resolution, level),
+                    &heightfield,
+                );
+                mesh
+            })
+            .collect();
+        Some(meshes)
+    }
+}
+
+pub struct ChunkStreamer {
+    generator: Arc<ChunkGenerator>,
+    workers: ChunkWorkers,
+    chunks: HashMap<ChunkCoord, Chunk>,
+    pending: HashMap<ChunkCoord, Arc<AtomicBool>>,
+    levels: Vec<LevelIndices>,
+    occluded: HashSet<ChunkCoord>,
+}
+
+impl ChunkStreamer {
+    pub fn new(generator: ChunkGenerator, device: &wgpu::Device) -> Self {
+        let generator = Arc::new(generator);
+        let settings = &generator.settings;
+        let level_count = settings.lod.levels(settings.resolution);
+        let levels = (0..level_count as usize)
+            .map(|level| {
+                // A neighbour can be at most this many levels coarser.
+                let steps = level_count - level as u32;
+                let stitching =
+                    PlaneStitching::new(settings.lod.resolution(settings.resolution, level), steps);
+                let index_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("chunk lod indices"),
+                    contents: bytemuck::cast_slice(&stitching.indices),
+                    usage: wgpu::BufferUsages::INDEX,
+                });
+                LevelIndices {
+                    index_buf,
+                    stitching,
+                }
+            })
+            .collect();
+        Self {
+            workers: ChunkWorkers::new(generator.clone()),
+            generator,
+            chunks: HashMap::new(),
+            pending: HashMap::new(),
+            levels,
+            occluded: HashSet::new(),
+        }
+    }
+
+    pub fn chunk_at(&self, world_position: Vector3<f32>) -> ChunkCoord {
+        let uv = vec2(world_position.x, -world_position.z)
+            / (WORLD_SCALE * self.generator.settings.size);
+        ChunkCoord {
+            x: uv.x.floor() as i32,
+            y: uv.y.floor() as i32,
+        }
+    }
+
+    // Edges next to a coarser chunk are drawn with the strip that matches its
+    // vertex spacing; the coarser side needs nothing special.
+    pub fn draws(&self) -> impl Iterator<Item = ChunkDraw<'_>> {
+        self.chunks.iter().map(|(coord, chunk)| {
+            let level = &self.levels[chunk.level];
+            let edge = |edge: PlaneEdge| {
+                let (dx, dy) = match edge {
+                    PlaneEdge::Left => (-1, 0),
+                    PlaneEdge::Right => (1, 0),
+                    PlaneEdge::Bottom => (0, -1),
+                    PlaneEdge::Top => (0, 1),
+                };
+                let neighbour = ChunkCoord {
+                    x: coord.x + dx,
+                    y: coord.y + dy,
+                };
+                let step_level = self
+                    .chunks
+                    .get(&neighbour)
+                    .map_or(0, |n| n.level.saturating_sub(chunk.level));
+                level.stitching.edge(edge, step_level)
+            };
+            ChunkDraw {
+                bounds: chunk.bounds,
+                occluded: self.occluded.contains(coord),
+                vertex_buf: &chunk.vertex_bufs[chunk.level],
+                instance_buf: &chunk.instance_buf,
+                index_buf: &level.index_buf,
+                ranges: [
+                    level.stitching.interior.clone(),
+                    edge(PlaneEdge::Left),
+                    edge(PlaneEdge::Right),
+                    edge(PlaneEdge::Bottom),
+                    edge(PlaneEdge::Top),
+                ],
+            }
+        })
+    }
+
+    // Queues the nearest missing chunks inside the load radius on the workers
+    // and uploads the ones they have finished. Chunks past the unload radius
+    // are dropped, or cancelled if they are still being built; the gap between
+    // the two radii stops chunks on the edge thrashing.
+    pub fn update(&mut self, device: &wgpu::Device, camera_position: Vector3<f32>) {
+        let settings = &self.generator.settings;
+        let centre = self.chunk_at(camera_position);
+        let in_range = |coord: &ChunkCoord, radius: i32| {
+            (coord.x - centre.x).abs() <= radius && (coord.y - centre.y).abs() <= radius
+        };
+        self.chunks
+            .retain(|coord, _| in_range(coord, settings.unload_radius));
+        self.pending.retain(|coord, cancelled| {
+            let keep = in_range(coord, settings.unload_radius);
+            if !keep {
+                cancelled.store(true, Ordering::Relaxed);
+            }
+            keep
+        });
+
+        // New chunks start on the level their distance asks for; loaded ones
+        // only move once they are clear of the boundary.
+        let camera_uv = vec2(camera_position.x, -camera_position.z) / WORLD_SCALE;
+        let select_level = |coord: ChunkCoord, current: Option<usize>| {
+            let distance = self.generator.distance(coord, camera_uv) * WORLD_SCALE;
+            settings.lod.select(settings.resolution, distance, current)
+        };
+        for (&coord, chunk) in &mut self.chunks {
+            chunk.level = select_level(coord, Some(chunk.level)).min(chunk.vertex_bufs.len() - 1);
+        }
+        for (coord, meshes) in self.workers.finished(settings.max_uploads_per_frame) {
+            if self.pending.remove(&coord).is_some() {
+                let mut chunk = self.upload(device, coord, meshes);
+                chunk.level = select_level(coord, None).min(chunk.vertex_bufs.len() - 1);
+                self.chunks.insert(coord, chunk);
+            }
+        }
+
+        // Only a few jobs are queued at a time so the queue is re-sorted
+        // around the camera as it moves instead of working through stale rings.
+        let load_radius = settings.load_radius;
+        let mut missing = (-load_radius..=load_radius)
+            .flat_map(|dy| {
+                (-load_radius..=load_radius).map(move |dx| ChunkCoord {
+                    x: centre.x + dx,
+                    y: centre.y + dy,
+                })
+            })
+            .filter(|coord| !self.chunks.contains_key(coord) && !self.pending.contains_key(coord))
+            .collect::<Vec<_>>();
+        missing.sort_by_key(|coord| (coord.x - centre.x).pow(2) + (coord.y - centre.y).pow(2));
+        let free = (2 * self.workers.len()).saturating_sub(self.pending.len());
+        for coord in missing.into_iter().take(free) {
+            let cancelled = Arc::new(AtomicBool::new(false));
+            self.workers.queue(coord, cancelled.clone());
+            self.pending.insert(coord, cancelled);
+        }
+        self.cull_occluded(camera_position);
+    }
+
+    // Walks the chunks out from the camera in square rings. Sight lines from
+    // the camera's chunk leave each ring before they reach the next, so the
+    // inner rings' ground is in front of everything in the outer ones.
+    fn cull_occluded(&mut self, camera_position: Vector3<f32>) {
+        self.occluded.clear();
+        if !self.generator.settings.occlusion_culling {
+            return;
+        }
+        let centre = self.chunk_at(camera_position);
+        let ring = |coord: &ChunkCoord| (coord.x - centre.x).abs().max((coord.y - centre.y).abs());
+        let mut coords = self.chunks.keys().copied().collect::<Vec<_>>();
+        coords.sort_by_key(ring);
+        let mut horizon = Horizon::new(Point3::from_vec(camera_position));
+        for coords in coords.chunk_by(|a, b| ring(a) == ring(b)) {
+            for coord in coords {
+                if horizon.hidden(&self.chunks[coord].bounds) {
+                    self.occluded.insert(*coord);
+                }
+            }
+            for coord in coords {
+                for occluder in &self.chunks[coord].occluders {
+                    horizon.add(occluder);
+                }
+            }
+        }
+    }
+
+    fn upload(&self, device: &wgpu::Device, coord: ChunkCoord, meshes: Vec<Mesh>) -> Chunk {
+        let size = self.generator.settings.size;
+        let vertex_bufs = meshes
+            .iter()
+            .map(|mesh| {
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("chunk vertices"),
+                    contents: bytemuck::cast_slice(mesh.vertices()),
+                    usage: wgpu::BufferUsages::VERTEX,
+                })
+            })
+            .collect();
+        let origin = self.generator.origin(coord);
+        // Only the transform is needed, the vertices are already on the GPU.
+        let entity = Entity::new(
+            Mesh::new(vec![], vec![]),
+            vec3(origin.x, 0.0, -origin.y) * WORLD_SCALE,
+            Quaternion::from_angle_x(Deg(-90.0)),
+            vec3(1.0, 1.0, 1.0) * WORLD_SCALE * size,
+        );
+        let bounds = meshes[0].bounds().transform(entity.get_model_matrix());
+        let occluders = self.occluders(&meshes, entity.get_model_matrix());
+        let instance_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("chunk entity data"),
+            contents: bytemuck::bytes_of(&entity.to_entity_data()),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        Chunk {
+            vertex_bufs,
+            bounds,
+            occluders,
+            level: 0,
+            instance_buf,
+        }
+    }
+
+    // Cell edges sit on the coarsest level's grid lines, so the triangles in a
+    // cell only use its own vertices on every level and can't dip below them.
+    fn occluders(&self, meshes: &[Mesh], model: Matrix4<f32>) -> Vec<Aabb> {
+        let settings = &self.generator.settings;
+        let resolution = settings.resolution;
+        let coarsest = settings.lod.resolution(resolution, meshes.len() - 1);
+        let cells = coarsest.min(OCCLUDER_CELLS);
+        let edge = |cell: u32| cell * coarsest / cells * (resolution / coarsest);
+        let vertices = meshes[0].vertices();
+        (0..cells)
+            .flat_map(|cu| (0..cells).map(move |cv| (cu, cv)))
+            .map(|(cu, cv)| {
+                Aabb::from_points(
+                    (edge(cu)..=edge(cu + 1))
+                        .flat_map(|u| (edge(cv)..=edge(cv + 1)).map(move |v| (u, v)))
+                        .map(|(u, v)| {
+                            let vertex = vertices[(u * (resolution + 1) + v) as usize];
+                            Point3::from_homogeneous(vertex.pos.into())
+                        }),
+                )
+                .transform(model)
+            })
+            .collect()
+    }
+}
+
+impl Drop for ChunkStreamer {
+    fn drop(&mut self) {
+        // Runs before the workers are dropped, so they skip whatever is still
+        // queued and abandon the chunks in hand instead of finishing them.
+        for cancelled in self.pending.values() {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fractal::TerrainPreset, noise::Noise, noise::NoiseKind};
+
+    fn generator() -> ChunkGenerator {
+        ChunkGenerator {
+            terrain: TerrainPreset::Mountains
+                .build(Noise::new(5), NoiseKind::Simplex)
+                .into(),
+            seed: 5,
+            hydraulic_erosion: HydraulicErosion::default(),
+            thermal_erosion: ThermalErosion::default(),
+            settings: ChunkSettings {
+                resolution: 16,
+                ..Default::default()
+            },
+        }
+    }
+
+    // Heights along x = `x` of a chunk's finest mesh, ordered by y.
+    fn column(mesh: &Mesh, x: f32) -> Vec<f32> {
+        let mut column: Vec<[f32; 4]> = mesh
+            .vertices()
+            .iter()
+            .map(|v| v.pos)
+            .filter(|p| p[0] == x)
+            .collect();
+        column.sort_by(|a, b| a[1].total_cmp(&b[1]));
+        column.iter().map(|p| p[2]).collect()
+    }
+
+    #[test]
+    fn neighbouring_chunks_meet_without_seams() {
+        let generator = generator();
+        let cancelled = AtomicBool::new(false);
+        let left = generator
+            .generate(ChunkCoord { x: -1, y: 2 }, &cancelled)
+            .unwrap();
+        let right = generator
+            .generate(ChunkCoord { x: 0, y: 2 }, &cancelled)
+            .unwrap();
+        let (left, right) = (column(&left[0], 1.0), column(&right[0], 0.0));
+        assert_eq!(left.len(), 17);
+        for (a, b) in left.iter().zip(&right) {
+            assert!((a - b).abs() < 1e-5, "{a} != {b}");
+        }
+    }
+}
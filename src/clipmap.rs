@@ -0,0 +1,502 @@
+use bytemuck::{Pod, Zeroable};
+use cgmath::*;
+use wgpu::vertex_attr_array;
+
+use crate::{
+    entity::{Entity, EntityData},
+    frustum::{Aabb, CullStats, Frustum},
+    heightfield::{HeightFunction, Heightfield},
+    light::PointLight,
+    vertex::{Mesh, MeshData},
+};
+
+#[derive(Debug, Clone)]
+pub struct ClipmapSettings {
+    // Quads along each side of the patch mesh every node is drawn with.
+    pub grid: u32,
+    // How far, in node widths, a level reaches before the next coarser one
+    // takes over. Has to stay well above 4 or neighbours more than one level
+    // apart can meet and crack.
+    pub range: f32,
+    // The outer fraction of each level's range over which vertices morph
+    // towards the coarser level.
+    pub morph: f32,
+}
+
+impl Default for ClipmapSettings {
+    fn default() -> Self {
+        Self {
+            grid: 32,
+            range: 6.0,
+            morph: 0.25,
+        }
+    }
+}
+
+// Per patch instance data: the terrain's transform, then where the patch sits
+// in terrain uv, its size and grid, and the distances it morphs between.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct PatchData {
+    entity: EntityData,
+    placement: [f32; 4],
+    morph: [f32; 4],
+}
+
+impl PatchData {
+    pub const LAYOUT: wgpu::VertexBufferLayout<'static> = wgpu::VertexBufferLayout {
+        array_stride: size_of::<PatchData>() as wgpu::BufferAddress,
+        step_mode: wgpu::VertexStepMode::Instance,
+        attributes: &vertex_attr_array![
+            4 => Float32x4,
+            5 => Float32x4,
+            6 => Float32x4,
+            7 => Float32x4,
+            8 => Float32x4,
+            9 => Float32x4,
+            10 => Float32x4,
+            11 => Float32x4,
+            12 => Float32x4,
+            13 => Float32x4,
+        ],
+    };
+}
+
+struct Flat;
+
+struct Selection<'a> {
+    camera_position: Point3<f32>,
+    visible: &'a dyn Fn(&Aabb) -> bool,
+}
+
+impl HeightFunction for Flat {
+    fn sample(&self, _u: f32, _v: f32) -> f32 {
+        0.0
+    }
+}
+
+// Draws the terrain from one small grid mesh instead of a full resolution
+// plane. The terrain's uv square is split as a quadtree; nodes near the camera
+// are subdivided, so the patches form rings that get coarser with distance,
+// and vs_clipmap lifts each patch off the height texture. Vertices near the
+// edge of a level's range slide onto the coarser grid (as in CDLOD), so the
+// levels meet without cracks or popping.
+pub struct Clipmap {
+    settings: ClipmapSettings,
+    // Level 0 is the finest; the last level is the single root node.
+    levels: u32,
+    // Min and max local height of every node, row by row, for each level.
+    bounds: Vec<Vec<(f32, f32)>>,
+    model: Matrix4<f32>,
+    entity_data: EntityData,
+    node_span: f32,
+    mesh_data: MeshData,
+    instance_buf: wgpu::Buffer,
+    instance_len: u32,
+    stats: CullStats,
+    // Patches for the shadow map, which can cast into view from outside it.
+    shadow_instance_buf: wgpu::Buffer,
+    shadow_instance_len: u32,
+    // The same for each point light with a shadow, within the light's reach.
+    point_shadow_instances: Vec<(wgpu::Buffer, u32)>,
+}
+
+impl Clipmap {
+    // `terrain` only supplies the transform; its mesh isn't used.
+    pub fn new(
+        device: &wgpu::Device,
+        heightfield: &Heightfield,
+        terrain: &Entity,
+        settings: ClipmapSettings,
+        point_shadows: usize,
+    ) -> Self {
+        // Enough levels that the finest patch has about one vertex per texel.
+        let texels = heightfield.width().max(heightfield.height()) - 1;
+        let levels = (texels as f32 / settings.grid as f32)
+            .max(1.0)
+            .log2()
+            .ceil() as u32
+            + 1;
+        let model = terrain.get_model_matrix();
+        // World width of a node, taking the longer side of the terrain.
+        let node_span = model
+            .x
+            .truncate()
+            .magnitude()
+            .max(model.y.truncate().magnitude());
+
+        let mut patch = Mesh::new(vec![], vec![]);
+        patch.generate_plane(settings.grid, &Flat);
+        let node_count: u32 = (0..levels).map(|level| 1 << (2 * level)).sum();
+        let instance_buf_descriptor = wgpu::BufferDescriptor {
+            label: Some("clipmap patches"),
+            size: (size_of::<PatchData>() as u32 * node_count) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        };
+        let instance_buf = device.create_buffer(&instance_buf_descriptor);
+        let shadow_instance_buf = device.create_buffer(&instance_buf_descriptor);
+        let point_shadow_instances = (0..point_shadows)
+            .map(|_| (device.create_buffer(&instance_buf_descriptor), 0))
+            .collect();
+
+        let mut clipmap = Self {
+            settings,
+            levels,
+            bounds: vec![],
+            model,
+            entity_data: terrain.to_entity_data(),
+            node_span,
+            mesh_data: patch.to_mesh_data(device),
+            instance_buf,
+            instance_len: 0,
+            stats: CullStats::default(),
+            shadow_instance_buf,
+            shadow_instance_len: 0,
+            point_shadow_instances,
+        };
+        clipmap.set_heights(heightfield);
+        clipmap
+    }
+
+    pub fn mesh_data(&self) -> &MeshData {
+        &self.mesh_data
+    }
+
+    pub fn instance_buf(&self) -> &wgpu::Buffer {
+        &self.instance_buf
+    }
+
+    pub fn instance_len(&self) -> u32 {
+        self.instance_len
+    }
+
+    pub fn shadow_instance_buf(&self) -> &wgpu::Buffer {
+        &self.shadow_instance_buf
+    }
+
+    pub fn shadow_instance_len(&self) -> u32 {
+        self.shadow_instance_len
+    }
+
+    pub fn point_shadow_instance_buf(&self, light: usize) -> &wgpu::Buffer {
+        &self.point_shadow_instances[light].0
+    }
+
+    pub fn point_shadow_instance_len(&self, light: usize) -> u32 {
+        self.point_shadow_instances[light].1
+    }
+
+    // Patches drawn and culled by the last update.
+    pub fn stats(&self) -> CullStats {
+        self.stats
+    }
+
+    // Rebuilds the node bounds; the heights themselves are read from the
+    // height texture, so this is all that's needed after erosion.
+    pub fn set_heights(&mut self, heightfield: &Heightfield) {
+        let finest = self.nodes_per_side(0);
+        let (width, height) = (heightfield.width(), heightfield.height());
+        let texel_range = |node: u32, texels: u32| {
+            let to_texel = |edge: u32| edge as f32 / finest as f32 * (texels - 1) as f32;
+            (
+                to_texel(node).floor() as i32,
+                to_texel(node + 1).ceil() as i32,
+            )
+        };
+        let mut level_bounds = Vec::with_capacity((finest * finest) as usize);
+        for y in 0..finest {
+            let (y0, y1) = texel_range(y, height);
+            for x in 0..finest {
+                let (x0, x1) = texel_range(x, width);
+                let node = (y0..=y1)
+                    .flat_map(|ty| (x0..=x1).map(move |tx| (tx, ty)))
+                    .map(|(tx, ty)| heightfield.get(tx, ty))
+                    .fold((f32::MAX, f32::MIN), |(lo, hi), h| (lo.min(h), hi.max(h)));
+                level_bounds.push(node);
+            }
+        }
+
+        let mut bounds = vec![level_bounds];
+        for level in 1..self.levels {
+            let side = self.nodes_per_side(level);
+            let finer = &bounds[level as usize - 1];
+            let level_bounds = (0..side)
+                .flat_map(|y| (0..side).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    [(0, 0), (1, 0), (0, 1), (1, 1)]
+                        .map(|(dx, dy)| finer[((2 * y + dy) * side * 2 + 2 * x + dx) as usize])
+                        .iter()
+                        .fold((f32::MAX, f32::MIN), |(lo, hi), &(min, max)| {
+                            (lo.min(min), hi.max(max))
+                        })
+                })
+                .collect();
+            bounds.push(level_bounds);
+        }
+        self.bounds = bounds;
+    }
+
+    // Picks the patches for this camera and uploads them.
+    pub fn update(
+        &mut self,
+        queue: &wgpu::Queue,
+        camera_position: Vector3<f32>,
+        frustum: &Frustum,
+    ) {
+        let mut stats = CullStats::default();
+        let patches = self.select(
+            camera_position,
+            &|bounds| frustum.intersects(bounds),
+            &mut stats,
+        );
+        queue.write_buffer(&self.instance_buf, 0, bytemuck::cast_slice(&patches));
+        self.instance_len = patches.len() as u32;
+        self.stats = stats;
+    }
+
+    // Same levels as update picks, culled against `casters` instead.
+    pub fn update_shadow_casters(
+        &mut self,
+        queue: &wgpu::Queue,
+        camera_position: Vector3<f32>,
+        casters: &Frustum,
+    ) {
+        let patches = self.select(
+            camera_position,
+            &|bounds| casters.intersects(bounds),
+            &mut CullStats::default(),
+        );
+        queue.write_buffer(&self.shadow_instance_buf, 0, bytemuck::cast_slice(&patches));
+        self.shadow_instance_len = patches.len() as u32;
+    }
+
+    // Same levels again for each shadowed point light, keeping the patches
+    // that come within its radius. Lights past the ones with a shadow are
+    // ignored.
+    pub fn update_point_shadow_casters(
+        &mut self,
+        queue: &wgpu::Queue,
+        camera_position: Vector3<f32>,
+        lights: &[PointLight],
+    ) {
+        for (i, light) in lights
+            .iter()
+            .take(self.point_shadow_instances.len())
+            .enumerate()
+        {
+            let centre = Point3::from_vec(light.position);
+            let patches = self.select(
+                camera_position,
+                &|bounds| bounds.distance(centre) <= light.radius,
+                &mut CullStats::default(),
+            );
+            let (buf, len) = &mut self.point_shadow_instances[i];
+            queue.write_buffer(buf, 0, bytemuck::cast_slice(&patches));
+            *len = patches.len() as u32;
+        }
+    }
+
+    // Patches outside `visible` are culled.
+    fn select(
+        &self,
+        camera_position: Vector3<f32>,
+        visible: &dyn Fn(&Aabb) -> bool,
+        stats: &mut CullStats,
+    ) -> Vec<PatchData> {
+        let selection = Selection {
+            camera_position: Point3::from_vec(camera_position),
+            visible,
+        };
+        let mut patches = vec![];
+        let root = self.levels - 1;
+        if !self.select_node(root, 0, 0, &selection, &mut patches, stats) {
+            patches.push(self.patch(root, 0, 0));
+            stats.drawn += 1;
+        }
+        patches
+    }
+
+    // Returns false, without drawing anything, when the node is out of its
+    // level's range and its parent has to cover it. A parent covers such a
+    // child with the child's own patch: being out of range, that patch is
+    // fully morphed and matches the parent's spacing exactly. Nodes outside
+    // the selection count as covered, so nothing below them is drawn.
+    fn select_node(
+        &self,
+        level: u32,
+        x: u32,
+        y: u32,
+        selection: &Selection,
+        patches: &mut Vec<PatchData>,
+        stats: &mut CullStats,
+    ) -> bool {
+        let bounds = self.world_bounds(level, x, y);
+        if !(selection.visible)(&bounds) {
+            stats.culled += 1;
+            return true;
+        }
+        let distance = bounds.distance(selection.camera_position);
+        if distance > self.range(level) {
+            return false;
+        }
+        if level == 0 || distance > self.range(level - 1) {
+            patches.push(self.patch(level, x, y));
+            stats.drawn += 1;
+            return true;
+        }
+        for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let (cx, cy) = (2 * x + dx, 2 * y + dy);
+            if !self.select_node(level - 1, cx, cy, selection, patches, stats) {
+                patches.push(self.patch(level - 1, cx, cy));
+                stats.drawn += 1;
+            }
+        }
+        true
+    }
+
+    fn nodes_per_side(&self, level: u32) -> u32 {
+        1 << (self.levels - 1 - level)
+    }
+
+    fn node_size(&self, level: u32) -> f32 {
+        1.0 / self.nodes_per_side(level) as f32
+    }
+
+    fn range(&self, level: u32) -> f32 {
+        self.settings.range * self.node_size(level) * self.node_span
+    }
+
+    fn world_bounds(&self, level: u32, x: u32, y: u32) -> Aabb {
+        let size = self.node_size(level);
+        let (min_h, max_h) =
+            self.bounds[level as usize][(y * self.nodes_per_side(level) + x) as usize];
+        let (u0, v0) = (x as f32 * size, y as f32 * size);
+        Aabb {
+            min: point3(u0, v0, min_h),
+            max: point3(u0 + size, v0 + size, max_h),
+        }
+        .transform(self.model)
+    }
+
+    fn patch(&self, level: u32, x: u32, y: u32) -> PatchData {
+        let size = self.node_size(level);
+        let morph_end = self.range(level);
+        PatchData {
+            entity: self.entity_data,
+            placement: [
+                x as f32 * size,
+                y as f32 * size,
+                size,
+                self.settings.grid as f32,
+            ],
+            morph: [morph_end * (1.0 - self.settings.morph), morph_end, 0.0, 0.0],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpu_test;
+
+    struct Slope;
+
+    impl HeightFunction for Slope {
+        fn sample(&self, u: f32, v: f32) -> f32 {
+            0.1 * u + 0.05 * v
+        }
+    }
+
+    // Only the buffers need a device; selection itself runs on the CPU.
+    fn clipmap(point_shadows: usize) -> Option<(Clipmap, wgpu::Queue)> {
+        let (device, queue) = gpu_test::fallback_device()?;
+        let heightfield = Heightfield::from_fn(1025, 1025, &Slope);
+        let terrain = Entity::new(
+            Mesh::new(vec![], vec![]),
+            vec3(-1.0, 0.0, 1.0),
+            Quaternion::from_angle_x(Deg(-90.0)),
+            vec3(2.0, 1.0, 2.0),
+        );
+        let clipmap = Clipmap::new(
+            &device,
+            &heightfield,
+            &terrain,
+            ClipmapSettings::default(),
+            point_shadows,
+        );
+        Some((clipmap, queue))
+    }
+
+    // (u, v, size) of each patch.
+    fn squares(patches: &[PatchData]) -> Vec<(f32, f32, f32)> {
+        patches
+            .iter()
+            .map(|p| (p.placement[0], p.placement[1], p.placement[2]))
+            .collect()
+    }
+
+    #[test]
+    fn patches_tile_the_terrain() {
+        let Some((clipmap, _)) = clipmap(0) else {
+            eprintln!("no fallback adapter, skipping the clipmap test");
+            return;
+        };
+        let patches = clipmap.select(vec3(-0.4, 0.3, 0.6), &|_| true, &mut CullStats::default());
+        let squares = squares(&patches);
+        let sizes: Vec<f32> = squares.iter().map(|s| s.2).collect();
+        assert!(sizes.iter().any(|&s| s == clipmap.node_size(0)));
+        assert!(sizes.iter().any(|&s| s >= clipmap.node_size(2)));
+
+        let area: f32 = squares.iter().map(|s| s.2 * s.2).sum();
+        assert!((area - 1.0).abs() < 1e-5);
+        for (i, a) in squares.iter().enumerate() {
+            for b in &squares[i + 1..] {
+                let overlap = |a0: f32, b0: f32| (a0 + a.2).min(b0 + b.2) - a0.max(b0);
+                assert!(overlap(a.0, b.0) <= 0.0 || overlap(a.1, b.1) <= 0.0);
+                // Patches that share an edge are at most one level apart.
+                let touching = overlap(a.0, b.0) >= 0.0 && overlap(a.1, b.1) >= 0.0;
+                let sharing_edge = touching && overlap(a.0, b.0).max(overlap(a.1, b.1)) > 0.0;
+                if sharing_edge {
+                    assert!(a.2.max(b.2) / a.2.min(b.2) <= 2.0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn point_shadows_only_keep_patches_within_reach() {
+        let Some((mut clipmap, queue)) = clipmap(2) else {
+            eprintln!("no fallback adapter, skipping the clipmap test");
+            return;
+        };
+        let camera_position = vec3(0.0, 0.5, 0.0);
+        let all = clipmap
+            .select(camera_position, &|_| true, &mut CullStats::default())
+            .len() as u32;
+        let light = |position: Vector3<f32>, radius: f32| PointLight {
+            radius,
+            ..PointLight::new(position, vec3(1.0, 1.0, 1.0))
+        };
+        // The third light has no shadow map and is left out.
+        clipmap.update_point_shadow_casters(
+            &queue,
+            camera_position,
+            &[
+                light(vec3(0.5, 0.3, 0.5), 0.2),
+                light(vec3(0.0, 0.3, 40.0), 1.0),
+                light(vec3(0.0, 0.3, 0.0), 100.0),
+            ],
+        );
+        let near = clipmap.point_shadow_instance_len(0);
+        assert!(near > 0 && near < all, "{near} of {all}");
+        assert_eq!(clipmap.point_shadow_instance_len(1), 0);
+
+        clipmap.update_point_shadow_casters(
+            &queue,
+            camera_position,
+            &[light(vec3(0.0, 0.3, 0.0), 100.0)],
+        );
+        assert_eq!(clipmap.point_shadow_instance_len(0), all);
+    }
+}
@@ -1,18 +1,33 @@
 use bytemuck::{Pod, Zeroable};
 use cgmath::*;
-use wgpu::{util::DeviceExt, vertex_attr_array};
+use wgpu::vertex_attr_array;
 
-use crate::vertex::{Mesh, MeshData};
+use crate::{
+    frustum::Aabb,
+    vertex::{Mesh, MeshData},
+};
 
 pub struct EntityWrapper {
     pub entity: Entity,
     pub mesh_data: MeshData,
+    // The mesh's own bounds, so culling doesn't walk the vertices every frame.
+    bounds: Aabb,
 }
 
 impl EntityWrapper {
     pub fn new(entity: Entity, device: &wgpu::Device) -> Self {
         let mesh_data = entity.mesh.to_mesh_data(device);
-        Self { entity, mesh_data }
+        let bounds = entity.mesh.bounds();
+        Self {
+            entity,
+            mesh_data,
+            bounds,
+        }
+    }
+
+    // World space bounds at the entity's current transform.
+    pub fn bounds(&self) -> Aabb {
+        self.bounds.transform(self.entity.get_model_matrix())
     }
 
     pub fn update_entity_position(&mut self, pos: cgmath::Vector3<f32>) {
@@ -44,7 +59,11 @@ impl Entity {
         }
     }
 
-    fn get_model_matrix(&self) -> cgmath::Matrix4<f32> {
+    pub fn mesh(&self) -> &Mesh {
+        &self.mesh
+    }
+
+    pub fn get_model_matrix(&self) -> cgmath::Matrix4<f32> {
         let mut model = cgmath::Matrix4::identity();
         model = model * cgmath::Matrix4::from_translation(self.position);
         model = model
@@ -0,0 +1,373 @@
+use cgmath::*;
+
+use crate::{heightfield::Heightfield, noise::Rng};
+
+// Droplet erosion after Hans Theobald Beyer's "Implementation of a method for
+// hydraulic erosion". Heights are normalized to 0..1 while the droplets run so
+// the same parameters work for every terrain preset.
+#[derive(Debug, Clone)]
+pub struct HydraulicErosion {
+    pub droplets: u32,
+    pub max_lifetime: u32,
+    pub radius: i32,
+    pub inertia: f32,
+    pub sediment_capacity: f32,
+    pub min_sediment_capacity: f32,
+    pub erode_speed: f32,
+    pub deposit_speed: f32,
+    pub evaporate_speed: f32,
+    pub gravity: f32,
+    pub initial_water: f32,
+    pub initial_speed: f32,
+}
+
+impl Default for HydraulicErosion {
+    fn default() -> Self {
+        Self {
+            droplets: 100_000,
+            max_lifetime: 30,
+            radius: 3,
+            inertia: 0.05,
+            sediment_capacity: 4.0,
+            min_sediment_capacity: 0.01,
+            erode_speed: 0.3,
+            deposit_speed: 0.3,
+            evaporate_speed: 0.01,
+            gravity: 4.0,
+            initial_water: 1.0,
+            initial_speed: 1.0,
+        }
+    }
+}
+
+impl HydraulicErosion {
+    pub fn apply(&self, heightfield: &mut Heightfield, seed: u64) {
+        let width = heightfield.width() as usize;
+        let height = heightfield.height() as usize;
+        if width < 2 || height < 2 {
+            return;
+        }
+        let (lo, hi) = heightfield.range();
+        let scale = (hi - lo).max(f32::EPSILON);
+        let map = heightfield.data_mut();
+        map.iter_mut().for_each(|h| *h = (*h - lo) / scale);
+
+        let brush = self.brush();
+        let mut rng = Rng::new(seed);
+        for _ in 0..self.droplets {
+            let mut pos_x = rng.next_f32() * (width - 1) as f32;
+            let mut pos_y = rng.next_f32() * (height - 1) as f32;
+            let mut dir_x = 0.0;
+            let mut dir_y = 0.0;
+            let mut speed = self.initial_speed;
+            let mut water = self.initial_water;
+            let mut sediment = 0.0;
+
+            for _ in 0..self.max_lifetime {
+                let node_x = pos_x as usize;
+                let node_y = pos_y as usize;
+                let cell_x = pos_x - node_x as f32;
+                let cell_y = pos_y - node_y as f32;
+
+                let (old_height, grad_x, grad_y) = height_and_gradient(map, width, pos_x, pos_y);
+                dir_x = dir_x * self.inertia - grad_x * (1.0 - self.inertia);
+                dir_y = dir_y * self.inertia - grad_y * (1.0 - self.inertia);
+                let len = (dir_x * dir_x + dir_y * dir_y).sqrt();
+                if len == 0.0 {
+                    break;
+                }
+                dir_x /= len;
+                dir_y /= len;
+                pos_x += dir_x;
+                pos_y += dir_y;
+                if pos_x < 0.0
+                    || pos_y < 0.0
+                    || pos_x >= (width - 1) as f32
+                    || pos_y >= (height - 1) as f32
+                {
+                    break;
+                }
+
+                let (new_height, _, _) = height_and_gradient(map, width, pos_x, pos_y);
+                let delta = new_height - old_height;
+                let capacity = (-delta * speed * water * self.sediment_capacity)
+                    .max(self.min_sediment_capacity);
+
+                if sediment > capacity || delta > 0.0 {
+                    let amount = if delta > 0.0 {
+                        delta.min(sediment)
+                    } else {
+                        (sediment - capacity) * self.deposit_speed
+                    };
+                    sediment -= amount;
+                    let i = node_y * width + node_x;
+                    map[i] += amount * (1.0 - cell_x) * (1.0 - cell_y);
+                    map[i + 1] += amount * cell_x * (1.0 - cell_y);
+                    map[i + width] += amount * (1.0 - cell_x) * cell_y;
+                    map[i + width + 1] += amount * cell_x * cell_y;
+                } else {
+                    let amount = ((capacity - sediment) * self.erode_speed).min(-delta);
+                    for &(dx, dy, weight) in &brush {
+                        let x = node_x as i32 + dx;
+                        let y = node_y as i32 + dy;
+                        if x < 0 || y < 0 || x >= width as i32 || y >= height as i32 {
+                            continue;
+                        }
+                        map[y as usize * width + x as usize] -= amount * weight;
+                    }
+                    sediment += amount;
+                }
+
+                speed = (speed * speed - delta * self.gravity).max(0.0).sqrt();
+                water *= 1.0 - self.evaporate_speed;
+            }
+        }
+
+        map.iter_mut().for_each(|h| *h = *h * scale + lo);
+    }
+
+    fn brush(&self) -> Vec<(i32, i32, f32)> {
+        let radius = self.radius.max(1);
+        let mut brush = Vec::new();
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                let dist = ((dx * dx + dy * dy) as f32).sqrt();
+                if dist < radius as f32 {
+                    brush.push((dx, dy, 1.0 - dist / radius as f32));
+                }
+            }
+        }
+        let total: f32 = brush.iter().map(|&(_, _, w)| w).sum();
+        brush.iter_mut().for_each(|(_, _, w)| *w /= total);
+        brush
+    }
+}
+
+fn height_and_gradient(map: &[f32], width: usize, x: f32, y: f32) -> (f32, f32, f32) {
+    let node_x = x as usize;
+    let node_y = y as usize;
+    let cell_x = x - node_x as f32;
+    let cell_y = y - node_y as f32;
+
+    let i = node_y * width + node_x;
+    let nw = map[i];
+    let ne = map[i + 1];
+    let sw = map[i + width];
+    let se = map[i + width + 1];
+
+    let grad_x = (ne - nw) * (1.0 - cell_y) + (se - sw) * cell_y;
+    let grad_y = (sw - nw) * (1.0 - cell_x) + (se - ne) * cell_x;
+    let h = nw * (1.0 - cell_x) * (1.0 - cell_y)
+        + ne * cell_x * (1.0 - cell_y)
+        + sw * (1.0 - cell_x) * cell_y
+        + se * cell_x * cell_y;
+    (h, grad_x, grad_y)
+}
+
+// Material slides downhill wherever the drop to a 4-neighbour exceeds the
+// talus angle. Each iteration first computes every cell's outflow and then
+// gathers inflow from the neighbours, so the result doesn't depend on cell
+// visiting order. shaders/erosion.wgsl must stay in step with this.
+#[derive(Debug, Clone)]
+pub struct ThermalErosion {
+    pub iterations: u32,
+    pub talus_angle: Deg<f32>,
+    pub strength: f32,
+}
+
+impl Default for ThermalErosion {
+    fn default() -> Self {
+        Self {
+            iterations: 50,
+            talus_angle: Deg(33.0),
+            strength: 0.5,
+        }
+    }
+}
+
+impl ThermalErosion {
+    pub fn talus_heights(&self, heightfield: &Heightfield) -> (f32, f32) {
+        let slope = Rad::from(self.talus_angle).0.tan();
+        (
+            slope / heightfield.width().saturating_sub(1).max(1) as f32,
+            slope / heightfield.height().saturating_sub(1).max(1) as f32,
+        )
+    }
+
+    pub fn apply(&self, heightfield: &mut Heightfield) {
+        let width = heightfield.width() as usize;
+        let height = heightfield.height() as usize;
+        let talus = self.talus_heights(heightfield);
+        let mut outflow = vec![[0.0f32; 4]; width * height];
+        for _ in 0..self.iterations {
+            let map = heightfield.data_mut();
+            for y in 0..height {
+                for x in 0..width {
+                    outflow[y * width + x] =
+                        thermal_outflow(map, width, height, x, y, talus, self.strength);
+                }
+            }
+            for y in 0..height {
+                for x in 0..width {
+                    let i = y * width + x;
+                    let out = outflow[i];
+                    let mut inflow = 0.0;
+                    if x > 0 {
+                        inflow += outflow[i - 1][0];
+                    }
+                    if x + 1 < width {
+                        inflow += outflow[i + 1][1];
+                    }
+                    if y > 0 {
+                        inflow += outflow[i - width][2];
+                    }
+                    if y + 1 < height {
+                        inflow += outflow[i + width][3];
+                    }
+                    map[i] = map[i] - (out[0] + out[1] + out[2] + out[3]) + inflow;
+                }
+            }
+        }
+    }
+}
+
+// Outflow towards +x, -x, +y, -y.
+fn thermal_outflow(
+    map: &[f32],
+    width: usize,
+    height: usize,
+    x: usize,
+    y: usize,
+    (talus_x, talus_y): (f32, f32),
+    strength: f32,
+) -> [f32; 4] {
+    let h = map[y * width + x];
+    let excess = |neighbour: Option<usize>, talus: f32| {
+        neighbour.map_or(0.0, |n| (h - map[n] - talus).max(0.0))
+    };
+    let excess = [
+        excess((x + 1 < width).then(|| y * width + x + 1), talus_x),
+        excess((x > 0).then(|| y * width + x - 1), talus_x),
+        excess((y + 1 < height).then(|| (y + 1) * width + x), talus_y),
+        excess((y > 0).then(|| (y - 1) * width + x), talus_y),
+    ];
+    let total = excess[0] + excess[1] + excess[2] + excess[3];
+    if total <= 0.0 {
+        return [0.0; 4];
+    }
+    let max = excess[0].max(excess[1]).max(excess[2]).max(excess[3]);
+    let moved = max * 0.5 * strength;
+    excess.map(|e| moved * e / total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A cone in the middle of a flat square.
+    fn hill(size: u32) -> Heightfield {
+        let centre = (size - 1) as f32 / 2.0;
+        let data = (0..size * size)
+            .map(|i| {
+                let x = (i % size) as f32 - centre;
+                let y = (i / size) as f32 - centre;
+                (1.0 - (x * x + y * y).sqrt() / centre).max(0.0) * 0.5
+            })
+            .collect();
+        Heightfield::from_data(size, size, data)
+    }
+
+    fn volume(heightfield: &Heightfield) -> f32 {
+        heightfield.data().iter().sum()
+    }
+
+    #[test]
+    fn droplets_are_deterministic_for_a_seed() {
+        let erosion = HydraulicErosion {
+            droplets: 500,
+            ..Default::default()
+        };
+        let mut a = hill(33);
+        let mut b = hill(33);
+        let mut c = hill(33);
+        erosion.apply(&mut a, 7);
+        erosion.apply(&mut b, 7);
+        erosion.apply(&mut c, 8);
+        assert_eq!(a.data(), b.data());
+        assert_ne!(a.data(), c.data());
+        assert_ne!(a.data(), hill(33).data());
+    }
+
+    #[test]
+    fn droplets_never_add_material() {
+        let mut heightfield = hill(33);
+        let before = volume(&heightfield);
+        HydraulicErosion {
+            droplets: 2000,
+            ..Default::default()
+        }
+        .apply(&mut heightfield, 1);
+        assert!(volume(&heightfield) <= before + 1e-3);
+    }
+
+    #[test]
+    fn droplets_leave_flat_ground_alone() {
+        let mut heightfield = Heightfield::from_data(9, 9, vec![0.25; 81]);
+        HydraulicErosion {
+            droplets: 100,
+            ..Default::default()
+        }
+        .apply(&mut heightfield, 1);
+        assert!(heightfield.data().iter().all(|&h| h == 0.25));
+    }
+
+    // The steepest drop to a 4-neighbour, less the talus height along that axis.
+    fn steepest_excess(heightfield: &Heightfield, erosion: &ThermalErosion) -> f32 {
+        let (talus_x, talus_y) = erosion.talus_heights(heightfield);
+        let (width, height) = (heightfield.width() as i32, heightfield.height() as i32);
+        let mut steepest = f32::MIN;
+        for y in 0..height {
+            for x in 0..width {
+                let h = heightfield.get(x, y);
+                if x + 1 < width {
+                    steepest = steepest.max((h - heightfield.get(x + 1, y)).abs() - talus_x);
+                }
+                if y + 1 < height {
+                    steepest = steepest.max((h - heightfield.get(x, y + 1)).abs() - talus_y);
+                }
+            }
+        }
+        steepest
+    }
+
+    #[test]
+    fn thermal_erosion_handles_empty_heightfields() {
+        let mut heightfield = Heightfield::from_data(0, 0, vec![]);
+        let erosion = ThermalErosion::default();
+        let (talus_x, talus_y) = erosion.talus_heights(&heightfield);
+        assert!(talus_x.is_finite() && talus_y.is_finite());
+        erosion.apply(&mut heightfield);
+        assert!(heightfield.data().is_empty());
+    }
+
+    #[test]
+    fn thermal_erosion_conserves_material() {
+        let mut heightfield = hill(33);
+        let before = volume(&heightfield);
+        ThermalErosion::default().apply(&mut heightfield);
+        assert!((volume(&heightfield) - before).abs() < before * 1e-4);
+        assert_ne!(heightfield.data(), hill(33).data());
+    }
+
+    #[test]
+    fn thermal_erosion_settles_at_the_talus_angle() {
+        let mut heightfield = hill(33);
+        let erosion = ThermalErosion {
+            iterations: 1000,
+            ..Default::default()
+        };
+        assert!(steepest_excess(&heightfield, &erosion) > 0.0);
+        erosion.apply(&mut heightfield);
+        assert!(steepest_excess(&heightfield, &erosion) < 1e-3);
+    }
+}
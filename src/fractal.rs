@@ -0,0 +1,239 @@
+use std::str::FromStr;
+
+use anyhow::*;
+
+use crate::{
+    heightfield::HeightFunction,
+    noise::{Noise, NoiseKind},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FractalMode {
+    Fbm,
+    Ridged,
+    Billow,
+}
+
+#[derive(Debug, Clone)]
+pub struct Fractal {
+    pub noise: Noise,
+    pub kind: NoiseKind,
+    pub mode: FractalMode,
+    pub octaves: u32,
+    pub frequency: f32,
+    pub lacunarity: f32,
+    pub gain: f32,
+    pub amplitude: f32,
+}
+
+impl Fractal {
+    pub fn new(noise: Noise, kind: NoiseKind, mode: FractalMode) -> Self {
+        Self {
+            noise,
+            kind,
+            mode,
+            octaves: 6,
+            frequency: 4.0,
+            lacunarity: 2.0,
+            gain: 0.5,
+            amplitude: 0.1,
+        }
+    }
+}
+
+impl HeightFunction for Fractal {
+    fn sample(&self, u: f32, v: f32) -> f32 {
+        let mut sum = 0.0;
+        let mut norm = 0.0;
+        let mut amplitude = 1.0;
+        let mut frequency = self.frequency;
+        let mut weight = 1.0;
+        for octave in 0..self.octaves {
+            // Shift each octave so the lattices don't all line up at the origin.
+            let offset = octave as f32 * 17.31;
+            let n = self
+                .noise
+                .sample2(self.kind, u * frequency + offset, v * frequency + offset);
+            let value = match self.mode {
+                FractalMode::Fbm => n,
+                FractalMode::Billow => n.abs() * 2.0 - 1.0,
+                FractalMode::Ridged => {
+                    let ridge = (1.0 - n.abs()).powi(2) * weight;
+                    weight = (ridge * 2.0).clamp(0.0, 1.0);
+                    ridge
+                }
+            };
+            sum += value * amplitude;
+            norm += amplitude;
+            amplitude *= self.gain;
+            frequency *= self.lacunarity;
+        }
+        sum / norm * self.amplitude
+    }
+}
+
+pub struct DomainWarp<H> {
+    pub source: H,
+    pub warp: Fractal,
+    pub strength: f32,
+}
+
+impl<H: HeightFunction> HeightFunction for DomainWarp<H> {
+    fn sample(&self, u: f32, v: f32) -> f32 {
+        let du = self.warp.sample(u, v);
+        let dv = self.warp.sample(u + 5.2, v + 1.3);
+        self.source
+            .sample(u + du * self.strength, v + dv * self.strength)
+    }
+}
+
+pub struct Terrace<H> {
+    pub source: H,
+    pub step_height: f32,
+    pub sharpness: f32,
+}
+
+impl<H: HeightFunction> HeightFunction for Terrace<H> {
+    fn sample(&self, u: f32, v: f32) -> f32 {
+        let h = self.source.sample(u, v) / self.step_height;
+        (h.floor() + (h - h.floor()).powf(self.sharpness)) * self.step_height
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TerrainPreset {
+    Mountains,
+    Dunes,
+    Plateaus,
+    Hills,
+}
+
+impl FromStr for TerrainPreset {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mountains" => Ok(Self::Mountains),
+            "dunes" => Ok(Self::Dunes),
+            "plateaus" => Ok(Self::Plateaus),
+            "hills" => Ok(Self::Hills),
+            _ => bail!("unknown preset {s}, expected mountains, dunes, plateaus or hills"),
+        }
+    }
+}
+
+impl TerrainPreset {
+    pub fn build(self, noise: Noise, kind: NoiseKind) -> Box<dyn HeightFunction + Send + Sync> {
+        let mut warp = Fractal::new(noise.clone(), kind, FractalMode::Fbm);
+        warp.octaves = 3;
+        warp.amplitude = 1.0;
+        match self {
+            Self::Mountains => {
+                let mut fractal = Fractal::new(noise, kind, FractalMode::Ridged);
+                fractal.octaves = 8;
+                fractal.frequency = 3.0;
+                fractal.amplitude = 0.25;
+                Box::new(DomainWarp {
+                    source: fractal,
+                    warp,
+                    strength: 0.05,
+                })
+            }
+            Self::Dunes => {
+                let mut fractal = Fractal::new(noise, kind, FractalMode::Ridged);
+                fractal.octaves = 3;
+                fractal.frequency = 12.0;
+                fractal.gain = 0.3;
+                fractal.amplitude = 0.03;
+                warp.frequency = 2.0;
+                Box::new(DomainWarp {
+                    source: fractal,
+                    warp,
+                    strength: 0.03,
+                })
+            }
+            Self::Plateaus => {
+                let mut fractal = Fractal::new(noise, kind, FractalMode::Fbm);
+                fractal.frequency = 3.0;
+                fractal.amplitude = 0.15;
+                Box::new(Terrace {
+                    source: fractal,
+                    step_height: 0.03,
+                    sharpness: 6.0,
+                })
+            }
+            Self::Hills => {
+                let mut fractal = Fractal::new(noise, kind, FractalMode::Billow);
+                fractal.octaves = 4;
+                fractal.frequency = 3.0;
+                fractal.amplitude = 0.06;
+                Box::new(fractal)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Ramp;
+
+    impl HeightFunction for Ramp {
+        fn sample(&self, u: f32, _v: f32) -> f32 {
+            u
+        }
+    }
+
+    fn grid() -> impl Iterator<Item = (f32, f32)> {
+        (0..32).flat_map(|y| (0..32).map(move |x| (x as f32 / 31.0, y as f32 / 31.0)))
+    }
+
+    #[test]
+    fn fractals_stay_within_their_amplitude() {
+        for kind in [NoiseKind::Perlin, NoiseKind::Simplex, NoiseKind::Value] {
+            for mode in [FractalMode::Fbm, FractalMode::Ridged, FractalMode::Billow] {
+                let fractal = Fractal::new(Noise::new(3), kind, mode);
+                let low = if mode == FractalMode::Ridged {
+                    0.0
+                } else {
+                    -fractal.amplitude
+                };
+                for (u, v) in grid() {
+                    let h = fractal.sample(u, v);
+                    assert!(
+                        (low..=fractal.amplitude).contains(&h),
+                        "{kind:?} {mode:?} gave {h}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn terraces_keep_step_edges_and_flatten_between() {
+        let terrace = Terrace {
+            source: Ramp,
+            step_height: 0.25,
+            sharpness: 2.0,
+        };
+        for step in 0..4 {
+            let h = step as f32 * 0.25;
+            assert!((terrace.sample(h, 0.0) - h).abs() < 1e-6);
+            assert!((terrace.sample(h + 0.125, 0.0) - (h + 0.0625)).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn presets_depend_only_on_the_seed() {
+        for name in ["mountains", "dunes", "plateaus", "hills"] {
+            let preset: TerrainPreset = name.parse().unwrap();
+            let a = preset.build(Noise::new(1), NoiseKind::Simplex);
+            let b = preset.build(Noise::new(1), NoiseKind::Simplex);
+            let c = preset.build(Noise::new(2), NoiseKind::Simplex);
+            assert!(grid().all(|(u, v)| a.sample(u, v) == b.sample(u, v)));
+            assert!(grid().any(|(u, v)| a.sample(u, v) != c.sample(u, v)));
+        }
+        assert!("cliffs".parse::<TerrainPreset>().is_err());
+    }
+}
@@ -0,0 +1,185 @@
+use std::ops::AddAssign;
+
+use cgmath::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl Aabb {
+    // An empty box that any point grows.
+    pub const EMPTY: Aabb = Aabb {
+        min: Point3::new(f32::MAX, f32::MAX, f32::MAX),
+        max: Point3::new(f32::MIN, f32::MIN, f32::MIN),
+    };
+
+    pub fn from_points(points: impl IntoIterator<Item = Point3<f32>>) -> Self {
+        points.into_iter().fold(Self::EMPTY, |bounds, p| Aabb {
+            min: point3(
+                bounds.min.x.min(p.x),
+                bounds.min.y.min(p.y),
+                bounds.min.z.min(p.z),
+            ),
+            max: point3(
+                bounds.max.x.max(p.x),
+                bounds.max.y.max(p.y),
+                bounds.max.z.max(p.z),
+            ),
+        })
+    }
+
+    pub fn corners(&self) -> [Point3<f32>; 8] {
+        let (a, b) = (self.min, self.max);
+        [
+            point3(a.x, a.y, a.z),
+            point3(b.x, a.y, a.z),
+            point3(a.x, b.y, a.z),
+            point3(b.x, b.y, a.z),
+            point3(a.x, a.y, b.z),
+            point3(b.x, a.y, b.z),
+            point3(a.x, b.y, b.z),
+            point3(b.x, b.y, b.z),
+        ]
+    }
+
+    // The box around this one after a transform.
+    pub fn transform(&self, matrix: Matrix4<f32>) -> Self {
+        Self::from_points(self.corners().map(|p| matrix.transform_point(p)))
+    }
+
+    pub fn distance(&self, point: Point3<f32>) -> f32 {
+        let nearest = point3(
+            point.x.clamp(self.min.x, self.max.x),
+            point.y.clamp(self.min.y, self.max.y),
+            point.z.clamp(self.min.z, self.max.z),
+        );
+        (point - nearest).magnitude()
+    }
+}
+
+// The six planes of a view projection, facing inwards, taken straight from the
+// matrix rows (Gribb and Hartmann) with wgpu's 0..1 depth range.
+#[derive(Debug, Clone, Copy)]
+pub struct Frustum {
+    planes: [Vector4<f32>; 6],
+}
+
+impl Frustum {
+    pub fn new(view_projection: Matrix4<f32>) -> Self {
+        let row = |i| view_projection.row(i);
+        let planes = [
+            row(3) + row(0),
+            row(3) - row(0),
+            row(3) + row(1),
+            row(3) - row(1),
+            row(2),
+            row(3) - row(2),
+        ];
+        Self { planes }
+    }
+
+    // Conservative: a box near a corner of the frustum can pass while being
+    // just outside it, which only costs a wasted draw.
+    pub fn intersects(&self, bounds: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // The box corner furthest along the plane's normal.
+            let corner = vec3(
+                if plane.x >= 0.0 {
+                    bounds.max.x
+                } else {
+                    bounds.min.x
+                },
+                if plane.y >= 0.0 {
+                    bounds.max.y
+                } else {
+                    bounds.min.y
+                },
+                if plane.z >= 0.0 {
+                    bounds.max.z
+                } else {
+                    bounds.min.z
+                },
+            );
+            plane.truncate().dot(corner) + plane.w >= 0.0
+        })
+    }
+}
+
+// How many objects made it past culling in a frame.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CullStats {
+    pub drawn: u32,
+    pub culled: u32,
+}
+
+impl CullStats {
+    // Counts an object and passes `visible` through.
+    pub fn count(&mut self, visible: bool) -> bool {
+        if visible {
+            self.drawn += 1;
+        } else {
+            self.culled += 1;
+        }
+        visible
+    }
+}
+
+impl AddAssign for CullStats {
+    fn add_assign(&mut self, other: Self) {
+        self.drawn += other.drawn;
+        self.culled += other.culled;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::OPENGL_TO_WGPU_MATRIX;
+
+    // Looking down -z from the origin, 90 degrees across, out to 100.
+    fn frustum() -> Frustum {
+        Frustum::new(OPENGL_TO_WGPU_MATRIX * perspective(Deg(90.0), 1.0, 0.1, 100.0))
+    }
+
+    fn cube(centre: Point3<f32>, half: f32) -> Aabb {
+        Aabb {
+            min: centre - vec3(half, half, half),
+            max: centre + vec3(half, half, half),
+        }
+    }
+
+    #[test]
+    fn boxes_in_view_pass() {
+        let frustum = frustum();
+        assert!(frustum.intersects(&cube(point3(0.0, 0.0, -10.0), 1.0)));
+        // Straddling the left plane.
+        assert!(frustum.intersects(&cube(point3(-10.5, 0.0, -10.0), 1.0)));
+        // Around the camera itself.
+        assert!(frustum.intersects(&cube(point3(0.0, 0.0, 0.0), 1.0)));
+    }
+
+    #[test]
+    fn boxes_outside_are_culled() {
+        let frustum = frustum();
+        assert!(!frustum.intersects(&cube(point3(0.0, 0.0, 10.0), 1.0)));
+        assert!(!frustum.intersects(&cube(point3(-15.0, 0.0, -10.0), 1.0)));
+        assert!(!frustum.intersects(&cube(point3(0.0, 15.0, -10.0), 1.0)));
+        assert!(!frustum.intersects(&cube(point3(0.0, 0.0, -110.0), 1.0)));
+    }
+
+    #[test]
+    fn transformed_bounds_contain_the_moved_corners() {
+        let bounds = cube(point3(1.0, 0.0, 0.0), 1.0);
+        let matrix =
+            Matrix4::from_translation(vec3(0.0, 5.0, 0.0)) * Matrix4::from_angle_y(Deg(90.0));
+        let moved = bounds.transform(matrix);
+        for corner in bounds.corners() {
+            let p = matrix.transform_point(corner);
+            assert!(moved.distance(p) < 1e-5);
+        }
+        assert!((moved.min - point3(-1.0, 4.0, -2.0)).magnitude() < 1e-5);
+        assert!((moved.max - point3(1.0, 6.0, 0.0)).magnitude() < 1e-5);
+    }
+}
@@ -0,0 +1,476 @@
+use std::path::Path;
+
+use anyhow::*;
+use cgmath::*;
+
+use crate::{
+    entity::Entity,
+    model::{self, Model, ModelMaterial, ModelPart},
+    texture::{Texture, TextureSource},
+    vertex::{Material, Mesh},
+};
+
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+const UNSIGNED_INT: u32 = 5125;
+const FLOAT: u32 = 5126;
+
+pub struct SceneNode<'a> {
+    pub name: &'a str,
+    pub entity: &'a Entity,
+    pub material: Option<&'a Material>,
+}
+
+// glTF is y up and right handed like the app, so positions and UVs go in as
+// they are and the importer rebuilds the same scene.
+pub fn save(path: &Path, nodes: &[SceneNode]) -> Result<()> {
+    let mut glb = GlbBuilder::default();
+    let mut materials: Vec<&Material> = vec![];
+    let mut json_nodes = vec![];
+    let mut json_meshes = vec![];
+    for (i, node) in nodes.iter().enumerate() {
+        let material = node.material.map(|material| {
+            match materials.iter().position(|m| std::ptr::eq(*m, material)) {
+                Some(index) => index,
+                None => {
+                    materials.push(material);
+                    materials.len() - 1
+                }
+            }
+        });
+        // Entity scales after rotating, which glTF's TRS nodes can't express
+        // and a node matrix has to decompose into, so the rotation and scale
+        // are baked into the vertices and the node is only translated.
+        let model = node.entity.get_model_matrix();
+        let mut mesh = Mesh::new(
+            node.entity.mesh().vertices().to_vec(),
+            node.entity.mesh().indices().to_vec(),
+        );
+        mesh.transform(Matrix4::from_cols(
+            model.x,
+            model.y,
+            model.z,
+            Vector4::unit_w(),
+        ));
+        json_meshes.push(glb.mesh(node.name, &mesh, material));
+        json_nodes.push(format!(
+            r#"{{"name":{},"mesh":{i},"translation":[{},{},{}]}}"#,
+            json_string(node.name),
+            model.w.x,
+            model.w.y,
+            model.w.z,
+        ));
+    }
+    let json_materials = materials
+        .iter()
+        .map(|material| glb.material(material))
+        .collect::<Vec<_>>();
+
+    let scene_nodes = (0..nodes.len())
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut json = format!(
+        r#"{{"asset":{{"version":"2.0","generator":"terrain-gen"}},"scene":0,"scenes":[{{"nodes":[{scene_nodes}]}}]"#
+    );
+    json_array(&mut json, "nodes", &json_nodes);
+    json_array(&mut json, "meshes", &json_meshes);
+    json_array(&mut json, "materials", &json_materials);
+    json_array(&mut json, "textures", &glb.textures);
+    json_array(&mut json, "images", &glb.images);
+    if !glb.textures.is_empty() {
+        json.push_str(r#","samplers":[{"magFilter":9729,"minFilter":9729}]"#);
+    }
+    json_array(&mut json, "accessors", &glb.accessors);
+    json_array(&mut json, "bufferViews", &glb.buffer_views);
+    json.push_str(&format!(
+        r#","buffers":[{{"byteLength":{}}}]}}"#,
+        glb.bin.len()
+    ));
+
+    std::fs::write(path, glb.finish(json))?;
+    Ok(())
+}
+
+// Reads .gltf or .glb through the gltf crate. Every triangle primitive of the
+// default scene becomes a part placed by its node's world transform; images are
+// re-encoded as PNG so they load through Texture::from_bytes like any other.
+pub fn load(path: &Path) -> Result<Model> {
+    let (document, buffers, images) = gltf::import(path)?;
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .context("no scene to import")?;
+
+    let mut parts = vec![];
+    let mut stack: Vec<(gltf::Node, Matrix4<f32>)> = scene
+        .nodes()
+        .map(|node| (node, Matrix4::identity()))
+        .collect();
+    while let Some((node, parent)) = stack.pop() {
+        let transform = parent * Matrix4::from(node.transform().matrix());
+        if let Some(mesh) = node.mesh() {
+            for primitive in mesh.primitives() {
+                if primitive.mode() != gltf::mesh::Mode::Triangles {
+                    log::warn!(
+                        "Skipping {:?} primitive in {}",
+                        primitive.mode(),
+                        path.display()
+                    );
+                    continue;
+                }
+                let name = node
+                    .name()
+                    .or(mesh.name())
+                    .map(String::from)
+                    .unwrap_or_else(|| format!("node{}", node.index()));
+                parts.push(ModelPart {
+                    name,
+                    mesh: primitive_mesh(&primitive, &buffers)?,
+                    transform,
+                    material: primitive.material().index(),
+                });
+            }
+        }
+        stack.extend(node.children().map(|child| (child, transform)));
+    }
+
+    let materials = document
+        .materials()
+        .map(|material| {
+            let name = material
+                .name()
+                .map(String::from)
+                .unwrap_or_else(|| format!("material{}", material.index().unwrap_or(0)));
+            let diffuse = material
+                .pbr_metallic_roughness()
+                .base_color_texture()
+                .map(|info| info.texture().source().index());
+            let normal = material
+                .normal_texture()
+                .map(|info| info.texture().source().index());
+            let source = |image: Option<usize>, suffix: &str| {
+                image
+                    .map(|index| png_source(&images[index], &format!("{name}_{suffix}.png")))
+                    .transpose()
+            };
+            Ok(ModelMaterial {
+                diffuse: source(diffuse, "diffuse")?,
+                normal: source(normal, "normal")?,
+                name,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Model { parts, materials })
+}
+
+fn primitive_mesh(primitive: &gltf::Primitive, buffers: &[gltf::buffer::Data]) -> Result<Mesh> {
+    let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+    let positions: Vec<[f32; 3]> = reader
+        .read_positions()
+        .context("primitive has no positions")?
+        .collect();
+    let normals: Option<Vec<[f32; 3]>> = reader.read_normals().map(|n| n.collect());
+    let tex_coords: Vec<[f32; 2]> = reader
+        .read_tex_coords(0)
+        .map(|t| t.into_f32().collect())
+        .unwrap_or_else(|| vec![[0.0, 0.0]; positions.len()]);
+    let colors: Vec<[f32; 4]> = reader
+        .read_colors(0)
+        .map(|c| c.into_rgba_f32().collect())
+        .unwrap_or_else(|| vec![[1.0; 4]; positions.len()]);
+    let base_color = primitive
+        .material()
+        .pbr_metallic_roughness()
+        .base_color_factor();
+    let indices: Vec<u32> = reader
+        .read_indices()
+        .map(|i| i.into_u32().collect())
+        .unwrap_or_else(|| (0..positions.len() as u32).collect());
+
+    let mut vertices = positions
+        .iter()
+        .enumerate()
+        .map(|(i, &pos)| {
+            let color = [0, 1, 2, 3].map(|c| colors[i][c] * base_color[c]);
+            let normal = normals.as_ref().map(|n| n[i]).unwrap_or([0.0, 1.0, 0.0]);
+            model::vertex(pos, normal, tex_coords[i], color)
+        })
+        .collect::<Vec<_>>();
+    if normals.is_none() {
+        model::smooth_normals(&mut vertices, &indices);
+    }
+    Ok(Mesh::new(vertices, indices))
+}
+
+fn png_source(image: &gltf::image::Data, name: &str) -> Result<TextureSource> {
+    use gltf::image::Format;
+    let (width, height, pixels) = (image.width, image.height, image.pixels.clone());
+    let img: image::DynamicImage = match image.format {
+        Format::R8 => image::GrayImage::from_raw(width, height, pixels).map(Into::into),
+        Format::R8G8 => image::GrayAlphaImage::from_raw(width, height, pixels).map(Into::into),
+        Format::R8G8B8 => image::RgbImage::from_raw(width, height, pixels).map(Into::into),
+        Format::R8G8B8A8 => image::RgbaImage::from_raw(width, height, pixels).map(Into::into),
+        format => bail!("{name} uses unsupported texture format {format:?}"),
+    }
+    .with_context(|| format!("{name} has the wrong number of pixels"))?;
+    let mut bytes = std::io::Cursor::new(vec![]);
+    img.write_to(&mut bytes, image::ImageFormat::Png)?;
+    Ok(TextureSource {
+        name: String::from(name),
+        bytes: bytes.into_inner(),
+    })
+}
+
+#[derive(Default)]
+struct GlbBuilder {
+    bin: Vec<u8>,
+    buffer_views: Vec<String>,
+    accessors: Vec<String>,
+    images: Vec<String>,
+    textures: Vec<String>,
+}
+
+impl GlbBuilder {
+    fn mesh(&mut self, name: &str, mesh: &Mesh, material: Option<usize>) -> String {
+        let vertices = mesh.vertices();
+        let positions: Vec<[f32; 3]> = vertices
+            .iter()
+            .map(|v| Vector4::from(v.pos).truncate().into())
+            .collect();
+        let normals: Vec<[f32; 3]> = vertices
+            .iter()
+            .map(|v| Vector4::from(v.normal).truncate().normalize().into())
+            .collect();
+        let tex_coords: Vec<[f32; 2]> = vertices.iter().map(|v| v.tex_coords).collect();
+        let colors: Vec<[f32; 4]> = vertices.iter().map(|v| v.color).collect();
+
+        let (min, max) = positions
+            .iter()
+            .fold(([f32::MAX; 3], [f32::MIN; 3]), |(min, max), p| {
+                (
+                    [min[0].min(p[0]), min[1].min(p[1]), min[2].min(p[2])],
+                    [max[0].max(p[0]), max[1].max(p[1]), max[2].max(p[2])],
+                )
+            });
+        let bounds = format!(
+            r#","min":[{},{},{}],"max":[{},{},{}]"#,
+            min[0], min[1], min[2], max[0], max[1], max[2]
+        );
+        let position = self.accessor(
+            bytemuck::cast_slice(&positions),
+            positions.len(),
+            FLOAT,
+            "VEC3",
+            ARRAY_BUFFER,
+            &bounds,
+        );
+        let normal = self.accessor(
+            bytemuck::cast_slice(&normals),
+            normals.len(),
+            FLOAT,
+            "VEC3",
+            ARRAY_BUFFER,
+            "",
+        );
+        let tex_coord = self.accessor(
+            bytemuck::cast_slice(&tex_coords),
+            tex_coords.len(),
+            FLOAT,
+            "VEC2",
+            ARRAY_BUFFER,
+            "",
+        );
+        let color = self.accessor(
+            bytemuck::cast_slice(&colors),
+            colors.len(),
+            FLOAT,
+            "VEC4",
+            ARRAY_BUFFER,
+            "",
+        );
+        let indices = self.accessor(
+            bytemuck::cast_slice(mesh.indices()),
+            mesh.indices().len(),
+            UNSIGNED_INT,
+            "SCALAR",
+            ELEMENT_ARRAY_BUFFER,
+            "",
+        );
+        let material = material
+            .map(|index| format!(r#","material":{index}"#))
+            .unwrap_or_default();
+        format!(
+            r#"{{"name":{},"primitives":[{{"attributes":{{"POSITION":{position},"NORMAL":{normal},"TEXCOORD_0":{tex_coord},"COLOR_0":{color}}},"indices":{indices}{material}}}]}}"#,
+            json_string(name)
+        )
+    }
+
+    fn material(&mut self, material: &Material) -> String {
+        let mut json = format!(
+            r#"{{"name":{},"pbrMetallicRoughness":{{"metallicFactor":0,"roughnessFactor":1"#,
+            json_string(&material.name)
+        );
+        if let Some(index) = self.texture(&material.diffuse_texture) {
+            json.push_str(&format!(r#","baseColorTexture":{{"index":{index}}}"#));
+        }
+        json.push('}');
+        if let Some(index) = self.texture(&material.normal_texture) {
+            json.push_str(&format!(r#","normalTexture":{{"index":{index}}}"#));
+        }
+        json.push('}');
+        json
+    }
+
+    // Only textures that remember their encoded file can be embedded; glTF
+    // viewers are only required to read PNG and JPEG.
+    fn texture(&mut self, texture: &Texture) -> Option<usize> {
+        let source = texture.source.as_ref()?;
+        let mime_type = match image::guess_format(&source.bytes).ok()? {
+            image::ImageFormat::Png => "image/png",
+            image::ImageFormat::Jpeg => "image/jpeg",
+            _ => return None,
+        };
+        let buffer_view = self.buffer_view(&source.bytes, None);
+        self.images.push(format!(
+            r#"{{"name":{},"bufferView":{buffer_view},"mimeType":"{mime_type}"}}"#,
+            json_string(&source.name)
+        ));
+        self.textures.push(format!(
+            r#"{{"sampler":0,"source":{}}}"#,
+            self.images.len() - 1
+        ));
+        Some(self.textures.len() - 1)
+    }
+
+    fn accessor(
+        &mut self,
+        bytes: &[u8],
+        count: usize,
+        component_type: u32,
+        ty: &str,
+        target: u32,
+        extra: &str,
+    ) -> usize {
+        let buffer_view = self.buffer_view(bytes, Some(target));
+        self.accessors.push(format!(
+            r#"{{"bufferView":{buffer_view},"componentType":{component_type},"count":{count},"type":"{ty}"{extra}}}"#
+        ));
+        self.accessors.len() - 1
+    }
+
+    fn buffer_view(&mut self, bytes: &[u8], target: Option<u32>) -> usize {
+        let offset = self.bin.len();
+        self.bin.extend_from_slice(bytes);
+        self.bin.resize(self.bin.len().next_multiple_of(4), 0);
+        let target = target
+            .map(|target| format!(r#","target":{target}"#))
+            .unwrap_or_default();
+        self.buffer_views.push(format!(
+            r#"{{"buffer":0,"byteOffset":{offset},"byteLength":{}{target}}}"#,
+            bytes.len()
+        ));
+        self.buffer_views.len() - 1
+    }
+
+    fn finish(self, json: String) -> Vec<u8> {
+        let mut json = json.into_bytes();
+        json.resize(json.len().next_multiple_of(4), b' ');
+        let length = 12 + 8 + json.len() + 8 + self.bin.len();
+
+        let mut out = Vec::with_capacity(length);
+        out.extend_from_slice(b"glTF");
+        out.extend_from_slice(&2u32.to_le_bytes());
+        out.extend_from_slice(&(length as u32).to_le_bytes());
+        out.extend_from_slice(&(json.len() as u32).to_le_bytes());
+        out.extend_from_slice(b"JSON");
+        out.extend_from_slice(&json);
+        out.extend_from_slice(&(self.bin.len() as u32).to_le_bytes());
+        out.extend_from_slice(b"BIN\0");
+        out.extend_from_slice(&self.bin);
+        out
+    }
+}
+
+fn json_array(json: &mut String, name: &str, items: &[String]) {
+    if !items.is_empty() {
+        json.push_str(&format!(r#","{name}":[{}]"#, items.join(",")));
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vertex::Vertex;
+
+    #[test]
+    fn saved_nodes_keep_scaled_and_rotated_entities_in_place() {
+        let vertex = |pos: [f32; 3]| Vertex {
+            pos: [pos[0], pos[1], pos[2], 1.0],
+            color: [1.0; 4],
+            normal: [0.0, 0.0, 1.0, 1.0],
+            tex_coords: [0.0; 2],
+        };
+        let mesh = Mesh::new(
+            vec![
+                vertex([0.0, 0.0, 0.0]),
+                vertex([1.0, 0.0, 0.0]),
+                vertex([0.0, 1.0, 0.0]),
+            ],
+            vec![0, 1, 2],
+        );
+        let entity = Entity::new(
+            mesh,
+            vec3(1.0, 2.0, 3.0),
+            Quaternion::from_angle_z(Deg(30.0)),
+            vec3(2.0, 0.5, 1.0),
+        );
+        let model = entity.get_model_matrix();
+        let expected: Vec<Vector3<f32>> = entity
+            .mesh()
+            .vertices()
+            .iter()
+            .map(|v| (model * Vector4::from(v.pos)).truncate())
+            .collect();
+
+        let dir = std::env::temp_dir().join(format!("terrain-gen-glb-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("triangle.glb");
+        save(
+            &path,
+            &[SceneNode {
+                name: "triangle",
+                entity: &entity,
+                material: None,
+            }],
+        )
+        .unwrap();
+        let loaded = load(&path);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let part = &loaded.unwrap().parts[0];
+        // A node matrix has to be a plain TRS, which T * S * R isn't.
+        assert_eq!(
+            part.transform,
+            Matrix4::from_translation(vec3(1.0, 2.0, 3.0))
+        );
+        for (vertex, expected) in part.mesh.vertices().iter().zip(&expected) {
+            let position = (part.transform * Vector4::from(vertex.pos)).truncate();
+            assert!((position - expected).magnitude() < 1e-5);
+        }
+    }
+}
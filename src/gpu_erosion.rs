@@ -0,0 +1,319 @@
+use anyhow::*;
+use bytemuck::{Pod, Zeroable};
+use wgpu::util::DeviceExt;
+
+use crate::{erosion::ThermalErosion, heightfield::Heightfield, texture::Texture};
+
+// Runs ThermalErosion on the heightfield texture the terrain shader samples,
+// so the result shows up without re-uploading any vertices.
+pub struct GpuThermalErosion {
+    iterations: u32,
+    size: wgpu::Extent3d,
+    next: wgpu::Texture,
+    outflow_pipeline: wgpu::ComputePipeline,
+    apply_pipeline: wgpu::ComputePipeline,
+    shared_bind_group: wgpu::BindGroup,
+    outflow_bind_group: wgpu::BindGroup,
+    apply_bind_group: wgpu::BindGroup,
+}
+
+impl GpuThermalErosion {
+    pub fn new(
+        device: &wgpu::Device,
+        heights: &Texture,
+        heightfield: &Heightfield,
+        erosion: &ThermalErosion,
+    ) -> Self {
+        let size = heights.texture.size();
+        let params_uniform_data = erosion.to_uniform_data(heightfield);
+        let params_uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("thermal erosion params"),
+            contents: bytemuck::bytes_of(&params_uniform_data),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let outflow = device.create_texture(&wgpu::wgt::TextureDescriptor {
+            label: Some("thermal erosion outflow"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let next = device.create_texture(&wgpu::wgt::TextureDescriptor {
+            label: Some("thermal erosion next heights"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R32Float,
+            usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let outflow_view = outflow.create_view(&wgpu::wgt::TextureViewDescriptor::default());
+        let next_view = next.create_view(&wgpu::wgt::TextureViewDescriptor::default());
+
+        let unfilterable_texture = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let storage_texture = |binding, format| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::StorageTexture {
+                access: wgpu::StorageTextureAccess::WriteOnly,
+                format,
+                view_dimension: wgpu::TextureViewDimension::D2,
+            },
+            count: None,
+        };
+        let shared_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("thermal_erosion_shared_bind_group_layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    unfilterable_texture(1),
+                ],
+            });
+        let outflow_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("thermal_erosion_outflow_bind_group_layout"),
+                entries: &[storage_texture(0, wgpu::TextureFormat::Rgba32Float)],
+            });
+        let apply_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("thermal_erosion_apply_bind_group_layout"),
+                entries: &[
+                    unfilterable_texture(1),
+                    storage_texture(2, wgpu::TextureFormat::R32Float),
+                ],
+            });
+
+        let shared_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("thermal_erosion_shared_bind_group"),
+            layout: &shared_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: params_uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&heights.view),
+                },
+            ],
+        });
+        let outflow_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("thermal_erosion_outflow_bind_group"),
+            layout: &outflow_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&outflow_view),
+            }],
+        });
+        let apply_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("thermal_erosion_apply_bind_group"),
+            layout: &apply_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&outflow_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&next_view),
+                },
+            ],
+        });
+
+        let shader = device.create_shader_module(wgpu::include_wgsl!("shaders/erosion.wgsl"));
+        let create_pipeline = |entry_point, layout: &wgpu::BindGroupLayout| {
+            let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: None,
+                bind_group_layouts: &[&shared_bind_group_layout, layout],
+                push_constant_ranges: &[],
+            });
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point: Some(entry_point),
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                cache: None,
+            })
+        };
+        let outflow_pipeline = create_pipeline("cs_outflow", &outflow_bind_group_layout);
+        let apply_pipeline = create_pipeline("cs_apply", &apply_bind_group_layout);
+
+        Self {
+            iterations: erosion.iterations,
+            size,
+            next,
+            outflow_pipeline,
+            apply_pipeline,
+            shared_bind_group,
+            outflow_bind_group,
+            apply_bind_group,
+        }
+    }
+
+    pub fn run(&self, device: &wgpu::Device, queue: &wgpu::Queue, heights: &Texture) {
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Thermal Erosion Encoder"),
+        });
+        let workgroups_x = self.size.width.div_ceil(8);
+        let workgroups_y = self.size.height.div_ceil(8);
+        for _ in 0..self.iterations {
+            {
+                let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                    label: None,
+                    timestamp_writes: None,
+                });
+                compute_pass.set_bind_group(0, &self.shared_bind_group, &[]);
+                compute_pass.set_pipeline(&self.outflow_pipeline);
+                compute_pass.set_bind_group(1, &self.outflow_bind_group, &[]);
+                compute_pass.dispatch_workgroups(workgroups_x, workgroups_y, 1);
+                compute_pass.set_pipeline(&self.apply_pipeline);
+                compute_pass.set_bind_group(1, &self.apply_bind_group, &[]);
+                compute_pass.dispatch_workgroups(workgroups_x, workgroups_y, 1);
+            }
+            encoder.copy_texture_to_texture(
+                self.next.as_image_copy(),
+                heights.texture.as_image_copy(),
+                self.size,
+            );
+        }
+        queue.submit(Some(encoder.finish()));
+    }
+}
+
+pub fn read_back(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    heights: &Texture,
+    heightfield: &mut Heightfield,
+) -> Result<()> {
+    let size = heights.texture.size();
+    let row_bytes = 4 * size.width;
+    let padded_row_bytes = row_bytes.next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("heightfield readback"),
+        size: (padded_row_bytes * size.height) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Readback Encoder"),
+    });
+    encoder.copy_texture_to_buffer(
+        heights.texture.as_image_copy(),
+        wgpu::TexelCopyBufferInfo {
+            buffer: &buffer,
+            layout: wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(padded_row_bytes),
+                rows_per_image: Some(size.height),
+            },
+        },
+        size,
+    );
+    queue.submit(Some(encoder.finish()));
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    buffer
+        .slice(..)
+        .map_async(wgpu::MapMode::Read, move |result| {
+            let _ = tx.send(result);
+        });
+    device.poll(wgpu::PollType::Wait)?;
+    rx.recv()??;
+
+    let mapped = buffer.slice(..).get_mapped_range();
+    for (row, dst) in heightfield
+        .data_mut()
+        .chunks_exact_mut(size.width as usize)
+        .enumerate()
+    {
+        let start = row * padded_row_bytes as usize;
+        dst.copy_from_slice(bytemuck::cast_slice(
+            &mapped[start..start + row_bytes as usize],
+        ));
+    }
+    Ok(())
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct ThermalParamsUniformData {
+    talus: [f32; 2],
+    strength: f32,
+    _padding: f32,
+}
+
+impl ThermalErosion {
+    pub fn to_uniform_data(&self, heightfield: &Heightfield) -> ThermalParamsUniformData {
+        let (talus_x, talus_y) = self.talus_heights(heightfield);
+        ThermalParamsUniformData {
+            talus: [talus_x, talus_y],
+            strength: self.strength,
+            _padding: 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fractal::{Fractal, FractalMode},
+        gpu_test,
+        noise::{Noise, NoiseKind},
+    };
+
+    #[test]
+    fn gpu_matches_cpu_reference() {
+        let Some((device, queue)) = gpu_test::fallback_device() else {
+            eprintln!("no fallback adapter, skipping the GPU erosion test");
+            return;
+        };
+        // Not square, so swapped axes would show up.
+        let terrain = Fractal::new(Noise::new(5), NoiseKind::Simplex, FractalMode::Ridged);
+        let heightfield = Heightfield::from_fn(61, 47, &terrain);
+        let erosion = ThermalErosion {
+            iterations: 20,
+            ..Default::default()
+        };
+        let mut reference = heightfield.clone();
+        erosion.apply(&mut reference);
+        assert_ne!(reference.data(), heightfield.data());
+
+        let heights = Texture::from_heightfield(&device, &queue, &heightfield, "test heights");
+        GpuThermalErosion::new(&device, &heights, &heightfield, &erosion)
+            .run(&device, &queue, &heights);
+        let mut eroded = heightfield.clone();
+        read_back(&device, &queue, &heights, &mut eroded).unwrap();
+        let max_error = reference
+            .data()
+            .iter()
+            .zip(eroded.data())
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max);
+        assert!(max_error < 1e-5, "GPU differs by {max_error}");
+    }
+}
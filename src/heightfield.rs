@@ -0,0 +1,133 @@
+use cgmath::*;
+
+pub trait HeightFunction {
+    fn sample(&self, u: f32, v: f32) -> f32;
+
+    fn normal(&self, u: f32, v: f32) -> Vector3<f32> {
+        let eps = 0.001;
+        let du = (self.sample(u + eps, v) - self.sample(u - eps, v)) / (2.0 * eps);
+        let dv = (self.sample(u, v + eps) - self.sample(u, v - eps)) / (2.0 * eps);
+        vec3(-du, -dv, 1.0).normalize()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Heightfield {
+    width: u32,
+    height: u32,
+    data: Vec<f32>,
+}
+
+impl Heightfield {
+    pub fn from_fn(width: u32, height: u32, f: &(impl HeightFunction + ?Sized)) -> Self {
+        let u_step = 1.0 / width.saturating_sub(1).max(1) as f32;
+        let v_step = 1.0 / height.saturating_sub(1).max(1) as f32;
+        let data = (0..height)
+            .flat_map(|y| (0..width).map(move |x| f.sample(x as f32 * u_step, y as f32 * v_step)))
+            .collect();
+        Self {
+            width,
+            height,
+            data,
+        }
+    }
+
+    pub fn from_data(width: u32, height: u32, data: Vec<f32>) -> Self {
+        assert_eq!(data.len(), width as usize * height as usize);
+        Self {
+            width,
+            height,
+            data,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn data(&self) -> &[f32] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [f32] {
+        &mut self.data
+    }
+
+    pub fn range(&self) -> (f32, f32) {
+        self.data
+            .iter()
+            .fold((f32::MAX, f32::MIN), |(lo, hi), &h| (lo.min(h), hi.max(h)))
+    }
+
+    pub fn get(&self, x: i32, y: i32) -> f32 {
+        let x = x.clamp(0, self.width as i32 - 1) as usize;
+        let y = y.clamp(0, self.height as i32 - 1) as usize;
+        self.data[y * self.width as usize + x]
+    }
+}
+
+impl HeightFunction for Heightfield {
+    fn sample(&self, u: f32, v: f32) -> f32 {
+        let x = u.clamp(0.0, 1.0) * (self.width - 1) as f32;
+        let y = v.clamp(0.0, 1.0) * (self.height - 1) as f32;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i32, y0 as i32);
+
+        let h00 = self.get(x0, y0);
+        let h10 = self.get(x0 + 1, y0);
+        let h01 = self.get(x0, y0 + 1);
+        let h11 = self.get(x0 + 1, y0 + 1);
+        let h0 = h00 + (h10 - h00) * fx;
+        let h1 = h01 + (h11 - h01) * fx;
+        h0 + (h1 - h0) * fy
+    }
+
+    fn normal(&self, u: f32, v: f32) -> Vector3<f32> {
+        let du = 1.0 / self.width.saturating_sub(1).max(1) as f32;
+        let dv = 1.0 / self.height.saturating_sub(1).max(1) as f32;
+        let dh_du = (self.sample(u + du, v) - self.sample(u - du, v)) / (2.0 * du);
+        let dh_dv = (self.sample(u, v + dv) - self.sample(u, v - dv)) / (2.0 * dv);
+        vec3(-dh_du, -dh_dv, 1.0).normalize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Plane;
+
+    impl HeightFunction for Plane {
+        fn sample(&self, u: f32, v: f32) -> f32 {
+            u + 2.0 * v
+        }
+    }
+
+    #[test]
+    fn from_fn_spans_the_uv_square() {
+        let heightfield = Heightfield::from_fn(3, 5, &Plane);
+        assert_eq!(heightfield.get(0, 0), 0.0);
+        assert_eq!(heightfield.get(2, 0), 1.0);
+        assert_eq!(heightfield.get(2, 4), 3.0);
+    }
+
+    #[test]
+    fn from_fn_handles_degenerate_sizes() {
+        assert!(Heightfield::from_fn(0, 0, &Plane).data().is_empty());
+        assert!(Heightfield::from_fn(0, 4, &Plane).data().is_empty());
+        assert_eq!(Heightfield::from_fn(1, 1, &Plane).data(), &[0.0]);
+    }
+
+    #[test]
+    fn sample_interpolates_between_texels() {
+        let heightfield = Heightfield::from_fn(5, 5, &Plane);
+        assert!((heightfield.sample(0.3, 0.6) - 1.5).abs() < 1e-5);
+        // Outside the square it clamps to the edge.
+        assert_eq!(heightfield.sample(-1.0, 2.0), 2.0);
+    }
+}
@@ -0,0 +1,229 @@
+use std::path::Path;
+
+use anyhow::*;
+
+use crate::heightfield::{HeightFunction, Heightfield};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeightmapFormat {
+    Image,
+    Exr,
+    R16,
+    R32F,
+}
+
+impl HeightmapFormat {
+    pub fn from_path(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("r16") | Some("raw") => Self::R16,
+            Some("r32") | Some("f32") => Self::R32F,
+            Some("exr") => Self::Exr,
+            _ => Self::Image,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HeightmapImport {
+    pub size: Option<(u32, u32)>,
+    pub scale: f32,
+    pub offset: f32,
+}
+
+impl Default for HeightmapImport {
+    fn default() -> Self {
+        Self {
+            size: None,
+            scale: 0.1,
+            offset: 0.0,
+        }
+    }
+}
+
+impl HeightmapImport {
+    pub fn load(&self, path: &Path) -> Result<Heightfield> {
+        let format = HeightmapFormat::from_path(path);
+        let (width, height, mut data): (u32, u32, Vec<f32>) = match format {
+            HeightmapFormat::Image => {
+                let img = image::open(path)
+                    .with_context(|| format!("unable to read heightmap {}", path.display()))?
+                    .to_luma16();
+                let data = img.pixels().map(|p| p.0[0] as f32 / 65535.0).collect();
+                (img.width(), img.height(), data)
+            }
+            HeightmapFormat::Exr => {
+                let img = image::open(path)
+                    .with_context(|| format!("unable to read heightmap {}", path.display()))?
+                    .to_rgb32f();
+                let data = img.pixels().map(|p| p.0[0]).collect();
+                (img.width(), img.height(), data)
+            }
+            HeightmapFormat::R16 | HeightmapFormat::R32F => {
+                let bytes = std::fs::read(path)
+                    .with_context(|| format!("unable to read heightmap {}", path.display()))?;
+                let texel_size = if format == HeightmapFormat::R16 { 2 } else { 4 };
+                let (width, height) = match self.size {
+                    Some(size) => size,
+                    None => square_size(bytes.len() / texel_size).with_context(|| {
+                        format!("{} is not square, pass its size", path.display())
+                    })?,
+                };
+                let expected = width as usize * height as usize * texel_size;
+                ensure!(
+                    bytes.len() == expected,
+                    "{} is {} bytes but {width}x{height} needs {expected}",
+                    path.display(),
+                    bytes.len(),
+                );
+                let data = if format == HeightmapFormat::R16 {
+                    bytes
+                        .chunks_exact(2)
+                        .map(|b| u16::from_le_bytes([b[0], b[1]]) as f32 / 65535.0)
+                        .collect()
+                } else {
+                    bytes
+                        .chunks_exact(4)
+                        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                        .collect()
+                };
+                (width, height, data)
+            }
+        };
+        ensure!(width >= 2 && height >= 2, "heightmap must be at least 2x2");
+
+        data.iter_mut()
+            .for_each(|h| *h = *h * self.scale + self.offset);
+        Ok(Heightfield::from_data(
+            width,
+            height,
+            flip_rows(&data, width),
+        ))
+    }
+}
+
+// Integer formats are stretched over the full 0..65535 range; the returned
+// (offset, scale) reproduce the original heights when passed back to
+// HeightmapImport. Float formats keep the heights as they are.
+pub fn save(heightfield: &Heightfield, path: &Path) -> Result<(f32, f32)> {
+    let (width, height) = (heightfield.width(), heightfield.height());
+    let rows = flip_rows(heightfield.data(), width);
+    let (lo, hi) = heightfield.range();
+    let scale = (hi - lo).max(f32::EPSILON);
+    let to_u16 = |h: f32| (((h - lo) / scale).clamp(0.0, 1.0) * 65535.0).round() as u16;
+
+    match HeightmapFormat::from_path(path) {
+        HeightmapFormat::Image => {
+            let pixels = rows.iter().map(|&h| to_u16(h)).collect();
+            image::ImageBuffer::<image::Luma<u16>, Vec<u16>>::from_raw(width, height, pixels)
+                .context("heightmap size mismatch")?
+                .save(path)?;
+            Ok((lo, scale))
+        }
+        HeightmapFormat::R16 => {
+            let bytes: Vec<u8> = rows.iter().flat_map(|&h| to_u16(h).to_le_bytes()).collect();
+            std::fs::write(path, bytes)?;
+            Ok((lo, scale))
+        }
+        HeightmapFormat::R32F => {
+            let bytes: Vec<u8> = rows.iter().flat_map(|h| h.to_le_bytes()).collect();
+            std::fs::write(path, bytes)?;
+            Ok((0.0, 1.0))
+        }
+        HeightmapFormat::Exr => {
+            let pixels = rows.iter().flat_map(|&h| [h, h, h]).collect();
+            image::Rgb32FImage::from_raw(width, height, pixels)
+                .context("heightmap size mismatch")?
+                .save(path)?;
+            Ok((0.0, 1.0))
+        }
+    }
+}
+
+pub fn save_normal_map(heightfield: &Heightfield, path: &Path) -> Result<()> {
+    let (width, height) = (heightfield.width(), heightfield.height());
+    let img = image::RgbImage::from_fn(width, height, |x, y| {
+        let u = x as f32 / (width - 1) as f32;
+        let v = (height - 1 - y) as f32 / (height - 1) as f32;
+        let n = heightfield.normal(u, v);
+        image::Rgb([n.x, n.y, n.z].map(|c| ((c * 0.5 + 0.5) * 255.0).round() as u8))
+    });
+    img.save(path)?;
+    Ok(())
+}
+
+// Image rows run top to bottom while the heightfield's v axis points away from
+// the camera, so rows are flipped on the way in and out to keep north at the top.
+fn flip_rows(data: &[f32], width: u32) -> Vec<f32> {
+    data.chunks_exact(width as usize)
+        .rev()
+        .flatten()
+        .copied()
+        .collect()
+}
+
+fn square_size(texels: usize) -> Option<(u32, u32)> {
+    let side = (texels as f64).sqrt().round() as usize;
+    (side * side == texels).then_some((side as u32, side as u32))
+}
+
+pub fn parse_size(s: &str) -> Result<(u32, u32)> {
+    let (width, height) = s
+        .split_once('x')
+        .with_context(|| format!("expected WIDTHxHEIGHT, got {s}"))?;
+    Ok((width.parse()?, height.parse()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_heightmaps_load_back() {
+        let (width, height) = (5, 3);
+        let data = (0..width * height)
+            .map(|i| ((i * 7) % 11) as f32 * 0.05 - 0.2)
+            .collect();
+        let heightfield = Heightfield::from_data(width, height, data);
+        let dir =
+            std::env::temp_dir().join(format!("terrain-gen-heightmap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        for name in ["terrain.png", "terrain.r16", "terrain.r32", "terrain.exr"] {
+            let path = dir.join(name);
+            let (offset, scale) = save(&heightfield, &path).unwrap();
+            let loaded = HeightmapImport {
+                size: Some((width, height)),
+                scale,
+                offset,
+            }
+            .load(&path)
+            .unwrap();
+            assert_eq!((loaded.width(), loaded.height()), (width, height), "{name}");
+            for (a, b) in loaded.data().iter().zip(heightfield.data()) {
+                assert!((a - b).abs() < 1e-4, "{name}: {a} != {b}");
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn raw_heightmaps_need_a_size_unless_square() {
+        let dir = std::env::temp_dir().join(format!("terrain-gen-raw-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let square = dir.join("square.r16");
+        std::fs::write(&square, [0u8; 2 * 9]).unwrap();
+        let oblong = dir.join("oblong.r16");
+        std::fs::write(&oblong, [0u8; 2 * 6]).unwrap();
+        let import = HeightmapImport::default();
+        let square = import.load(&square);
+        let oblong = import.load(&oblong);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(square.unwrap().width(), 3);
+        assert!(oblong.is_err());
+    }
+}
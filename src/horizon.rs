@@ -0,0 +1,135 @@
+use std::f32::consts::TAU;
+
+use cgmath::*;
+
+use crate::frustum::Aabb;
+
+const BINS: usize = 1024;
+
+// How far up the eye can see in every direction around it, as the steepest
+// slope (rise over flat distance) blocked by terrain added so far. Occluders
+// have to be added nearest first, and a box is only hidden by ones that lie
+// entirely in front of it.
+pub struct Horizon {
+    eye: Point3<f32>,
+    slopes: Vec<f32>,
+}
+
+// The part of the view around the eye a box covers, seen from above.
+struct Span {
+    start: f32,
+    end: f32,
+    near: f32,
+    far: f32,
+}
+
+impl Horizon {
+    pub fn new(eye: Point3<f32>) -> Self {
+        Self {
+            eye,
+            slopes: vec![f32::NEG_INFINITY; BINS],
+        }
+    }
+
+    // Whether every line of sight to the box passes under the horizon.
+    pub fn hidden(&self, bounds: &Aabb) -> bool {
+        let Some(span) = self.span(bounds) else {
+            return false;
+        };
+        let rise = bounds.max.y - self.eye.y;
+        let slope = rise / if rise > 0.0 { span.near } else { span.far };
+        let bin = |angle: f32| (angle / TAU * BINS as f32).floor() as i64;
+        (bin(span.start)..=bin(span.end))
+            .all(|i| self.slopes[i.rem_euclid(BINS as i64) as usize] > slope)
+    }
+
+    // Raises the horizon behind a patch of ground that is nowhere lower than
+    // the bottom of `bounds`. Only directions in which every sight line
+    // crosses the patch are raised.
+    pub fn add(&mut self, bounds: &Aabb) {
+        let Some(span) = self.span(bounds) else {
+            return;
+        };
+        let rise = bounds.min.y - self.eye.y;
+        let slope = rise / if rise > 0.0 { span.far } else { span.near };
+        let bin = |angle: f32| angle / TAU * BINS as f32;
+        let (first, last) = (bin(span.start).ceil() as i64, bin(span.end).floor() as i64);
+        for i in first..last {
+            let i = i.rem_euclid(BINS as i64) as usize;
+            self.slopes[i] = self.slopes[i].max(slope);
+        }
+    }
+
+    // None when the eye is above the box, which then covers every direction.
+    fn span(&self, bounds: &Aabb) -> Option<Span> {
+        let eye = vec2(self.eye.x, self.eye.z);
+        let (min, max) = (
+            vec2(bounds.min.x, bounds.min.z),
+            vec2(bounds.max.x, bounds.max.z),
+        );
+        let nearest = vec2(eye.x.clamp(min.x, max.x), eye.y.clamp(min.y, max.y));
+        let near = (nearest - eye).magnitude();
+        if near <= 0.0 {
+            return None;
+        }
+        let corners = [min, vec2(max.x, min.y), vec2(min.x, max.y), max].map(|c| c - eye);
+        let far = corners.iter().map(|c| c.magnitude()).fold(0.0, f32::max);
+        // Angles are taken relative to the centre so the span doesn't wrap.
+        let centre = (min + max) * 0.5 - eye;
+        let centre_angle = centre.y.atan2(centre.x);
+        let (low, high) = corners
+            .iter()
+            .map(|c| (c.y.atan2(c.x) - centre_angle + TAU * 1.5).rem_euclid(TAU) - TAU * 0.5)
+            .fold((f32::MAX, f32::MIN), |(lo, hi), a| (lo.min(a), hi.max(a)));
+        Some(Span {
+            start: centre_angle + low,
+            end: centre_angle + high,
+            near,
+            far,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aabb(min: [f32; 3], max: [f32; 3]) -> Aabb {
+        Aabb {
+            min: min.into(),
+            max: max.into(),
+        }
+    }
+
+    // A plateau 3 high just in front of an eye 1 up, looking down -z.
+    fn horizon() -> Horizon {
+        let mut horizon = Horizon::new(point3(0.0, 1.0, 0.0));
+        horizon.add(&aabb([-5.0, 3.0, -3.0], [5.0, 3.5, -2.0]));
+        horizon
+    }
+
+    #[test]
+    fn nothing_is_hidden_without_occluders() {
+        let horizon = Horizon::new(point3(0.0, 1.0, 0.0));
+        assert!(!horizon.hidden(&aabb([-1.0, 0.0, -20.0], [1.0, 0.5, -19.0])));
+    }
+
+    #[test]
+    fn low_boxes_behind_a_ridge_are_hidden() {
+        let horizon = horizon();
+        assert!(horizon.hidden(&aabb([-1.0, 0.0, -20.0], [1.0, 2.0, -19.0])));
+    }
+
+    #[test]
+    fn boxes_over_or_beside_a_ridge_are_visible() {
+        let horizon = horizon();
+        // Tall enough to poke over it.
+        assert!(!horizon.hidden(&aabb([-1.0, 0.0, -20.0], [1.0, 20.0, -19.0])));
+        // Off to the side of it.
+        assert!(!horizon.hidden(&aabb([18.0, 0.0, -1.0], [20.0, 0.5, 1.0])));
+        // Wider than it, seen from behind.
+        assert!(!horizon.hidden(&aabb([-200.0, 0.0, -20.0], [200.0, 0.5, -19.0])));
+        // Around the eye.
+        assert!(!horizon.hidden(&aabb([-1.0, 0.0, -1.0], [1.0, 0.5, 1.0])));
+    }
+}
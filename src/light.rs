@@ -1,28 +1,295 @@
+use std::marker::PhantomData;
+
+use anyhow::*;
 use bytemuck::{Pod, Zeroable};
 use cgmath::*;
+
 pub struct PointLight {
-    pub position: Vector4<f32>,
-    ambient_color: Vector3<f32>,
+    pub position: Vector3<f32>,
+    pub color: Vector3<f32>,
+    pub intensity: f32,
+    // Distance at which the light has faded out completely.
+    pub radius: f32,
 }
 
 impl PointLight {
-    pub fn new(position: Vector4<f32>, ambient_color: Vector3<f32>) -> Self {
+    pub fn new(position: Vector3<f32>, color: Vector3<f32>) -> Self {
         Self {
             position,
-            ambient_color,
+            color,
+            intensity: 1.0,
+            radius: 10.0,
+        }
+    }
+
+    pub fn to_data(&self) -> PointLightData {
+        PointLightData {
+            position: self.position.extend(self.radius).into(),
+            color: self.color.extend(self.intensity).into(),
+        }
+    }
+}
+
+// The radius rides in position.w and the intensity in color.a.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct PointLightData {
+    position: [f32; 4],
+    color: [f32; 4],
+}
+
+pub struct DirectionalLight {
+    // The way the light travels, from the light into the scene.
+    pub direction: Vector3<f32>,
+    pub color: Vector3<f32>,
+    pub intensity: f32,
+}
+
+impl DirectionalLight {
+    // The sun at `hour` (0 to 24): it rises in the east (+x) at 6, is highest
+    // at noon, somewhat to the south, and sets in the west at 18. It's orange
+    // near the horizon and close to white by mid morning. At night a faint
+    // blue moon shines from the opposite side; both fade out at the horizon so
+    // the light doesn't jump when they swap.
+    pub fn sun(hour: f32) -> Self {
+        let angle = Rad((hour - 6.0) / 12.0 * std::f32::consts::PI);
+        let tilt = Deg(30.0);
+        let (s, c) = angle.sin_cos();
+        let to_sun = vec3(c, s * tilt.cos(), s * tilt.sin());
+        let elevation = to_sun.y;
+        if elevation < 0.0 {
+            return Self {
+                direction: to_sun,
+                color: vec3(0.25, 0.3, 0.5),
+                intensity: 0.2 * smoothstep(0.0, 0.15, -elevation),
+            };
+        }
+
+        let horizon = vec3(1.0, 0.55, 0.25);
+        let noon = vec3(1.0, 0.95, 0.85);
+        Self {
+            direction: -to_sun,
+            color: horizon.lerp(noon, smoothstep(0.0, 0.7, elevation)),
+            intensity: 0.8 * smoothstep(0.0, 0.15, elevation),
         }
     }
 
-    pub fn to_uniform_data(&self) -> PointLightUniformData {
-        let pos = self.position.into();
-        let diffuse_color = Point3::from_vec(self.ambient_color).to_homogeneous().into();
-        PointLightUniformData { pos, diffuse_color }
+    pub fn to_uniform_data(&self) -> DirectionalLightUniformData {
+        DirectionalLightUniformData {
+            direction: self.direction.normalize().extend(0.0).into(),
+            color: self.color.extend(self.intensity).into(),
+        }
     }
 }
 
+// The intensity rides in color.a.
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
-pub struct PointLightUniformData {
-    pos: [f32; 4],
-    diffuse_color: [f32; 4],
+pub struct DirectionalLightUniformData {
+    direction: [f32; 4],
+    color: [f32; 4],
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+pub struct SpotLight {
+    pub position: Vector3<f32>,
+    // Where the cone points.
+    pub direction: Vector3<f32>,
+    pub color: Vector3<f32>,
+    pub intensity: f32,
+    // Angles from the cone's axis: full brightness inside the inner one,
+    // fading to nothing at the outer one.
+    pub inner_angle: Deg<f32>,
+    pub outer_angle: Deg<f32>,
+    // Distance at which the light has faded out completely.
+    pub radius: f32,
+}
+
+impl SpotLight {
+    pub fn new(position: Vector3<f32>, direction: Vector3<f32>, color: Vector3<f32>) -> Self {
+        Self {
+            position,
+            direction,
+            color,
+            intensity: 1.0,
+            inner_angle: Deg(15.0),
+            outer_angle: Deg(25.0),
+            radius: 10.0,
+        }
+    }
+
+    pub fn to_data(&self) -> SpotLightData {
+        SpotLightData {
+            position: self.position.extend(self.radius).into(),
+            direction: self.direction.normalize().extend(0.0).into(),
+            color: self.color.extend(self.intensity).into(),
+            cone: [self.inner_angle.cos(), self.outer_angle.cos(), 0.0, 0.0],
+        }
+    }
+}
+
+// As PointLightData, plus the cosines of the inner and outer angles in cone.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct SpotLightData {
+    position: [f32; 4],
+    direction: [f32; 4],
+    color: [f32; 4],
+    cone: [f32; 4],
+}
+
+// A lamp hung `height` world units above the terrain at plane uv, shining
+// straight down.
+#[derive(Debug, Clone)]
+pub struct LampPlacement {
+    pub uv: (f32, f32),
+    pub height: f32,
+}
+
+pub fn parse_lamp(s: &str) -> Result<LampPlacement> {
+    let values = s
+        .split(',')
+        .map(|v| v.parse::<f32>())
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let (uv, height) = match values[..] {
+        [u, v] => ((u, v), 0.3),
+        [u, v, height] => ((u, v), height),
+        _ => bail!("expected U,V[,HEIGHT], got {s}"),
+    };
+    Ok(LampPlacement { uv, height })
+}
+
+const HEADER_SIZE: usize = 16;
+
+// A list of lights as the shader's storage buffers see them: the count,
+// padded out to the lights' alignment, then the lights themselves.
+pub struct LightBuffer<T> {
+    buffer: wgpu::Buffer,
+    capacity: usize,
+    light: PhantomData<T>,
+}
+
+impl<T: Pod> LightBuffer<T> {
+    pub fn new(device: &wgpu::Device, label: &str, capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: (HEADER_SIZE + size_of::<T>() * capacity) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        Self {
+            buffer,
+            capacity,
+            light: PhantomData,
+        }
+    }
+
+    pub fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    // The buffer is sized once, so there can't be more lights than it was
+    // made for.
+    pub fn write(&self, queue: &wgpu::Queue, lights: &[T]) {
+        assert!(lights.len() <= self.capacity, "too many lights");
+        let mut header = [0u32; HEADER_SIZE / 4];
+        header[0] = lights.len() as u32;
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&header));
+        if !lights.is_empty() {
+            queue.write_buffer(
+                &self.buffer,
+                HEADER_SIZE as wgpu::BufferAddress,
+                bytemuck::cast_slice(lights),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sun_rises_in_the_east_and_peaks_at_noon() {
+        let dawn = DirectionalLight::sun(6.0);
+        assert!((dawn.direction - vec3(-1.0, 0.0, 0.0)).magnitude() < 1e-5);
+        let elevation = |hour: f32| -DirectionalLight::sun(hour).direction.y;
+        assert!(elevation(12.0) > elevation(9.0));
+        assert!(elevation(12.0) > elevation(15.0));
+        assert!((elevation(9.0) - elevation(15.0)).abs() < 1e-5);
+        assert!((DirectionalLight::sun(12.0).intensity - 0.8).abs() < 1e-5);
+    }
+
+    #[test]
+    fn light_fades_through_the_horizon() {
+        for hour in [6.0, 18.0] {
+            for offset in [-0.01, 0.0, 0.01] {
+                assert!(DirectionalLight::sun(hour + offset).intensity < 1e-3);
+            }
+        }
+        // The moon still shines down at midnight.
+        let moon = DirectionalLight::sun(0.0);
+        assert!(moon.direction.y < 0.0);
+        assert!(moon.intensity > 0.0);
+    }
+
+    #[test]
+    fn lamps_parse_with_an_optional_height() {
+        let lamp = parse_lamp("0.25,0.75").unwrap();
+        assert_eq!((lamp.uv, lamp.height), ((0.25, 0.75), 0.3));
+        let lamp = parse_lamp("0.5,0.5,2").unwrap();
+        assert_eq!((lamp.uv, lamp.height), ((0.5, 0.5), 2.0));
+        assert!(parse_lamp("0.5").is_err());
+        assert!(parse_lamp("0.5,x").is_err());
+    }
+
+    // The offset of the `lights` array in a storage struct of shader.wgsl,
+    // and its stride.
+    fn light_list_layout(module: &wgpu::naga::Module, name: &str) -> (u32, u32) {
+        use wgpu::naga::{TypeInner, proc::Layouter};
+
+        let mut layouter = Layouter::default();
+        layouter.update(module.to_ctx()).unwrap();
+        let (_, ty) = module
+            .types
+            .iter()
+            .find(|(_, ty)| ty.name.as_deref() == Some(name))
+            .unwrap();
+        let TypeInner::Struct { members, .. } = &ty.inner else {
+            panic!("{name} isn't a struct");
+        };
+        let lights = members
+            .iter()
+            .find(|m| m.name.as_deref() == Some("lights"))
+            .unwrap();
+        let TypeInner::Array { stride, .. } = module.types[lights.ty].inner else {
+            panic!("{name}.lights isn't an array");
+        };
+        (lights.offset, stride)
+    }
+
+    #[test]
+    fn light_buffers_match_the_shader() {
+        let module =
+            wgpu::naga::front::wgsl::parse_str(include_str!("shaders/shader.wgsl")).unwrap();
+        wgpu::naga::valid::Validator::new(
+            wgpu::naga::valid::ValidationFlags::all(),
+            wgpu::naga::valid::Capabilities::all(),
+        )
+        .validate(&module)
+        .unwrap();
+        assert_eq!(
+            light_list_layout(&module, "PointLights"),
+            (HEADER_SIZE as u32, size_of::<PointLightData>() as u32)
+        );
+        assert_eq!(
+            light_list_layout(&module, "SpotLights"),
+            (HEADER_SIZE as u32, size_of::<SpotLightData>() as u32)
+        );
+    }
 }
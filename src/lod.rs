@@ -0,0 +1,99 @@
+#[derive(Debug, Clone)]
+pub struct LodSettings {
+    pub levels: u32,
+    pub distance: f32,
+    pub hysteresis: f32,
+}
+
+impl Default for LodSettings {
+    fn default() -> Self {
+        Self {
+            levels: 4,
+            distance: 0.75,
+            hysteresis: 0.1,
+        }
+    }
+}
+
+impl LodSettings {
+    // Level n halves the resolution n times and is used out to distance * 2^n;
+    // the last level covers everything further away. Only as many levels as
+    // the resolution can be halved evenly are used, and the coarsest keeps at
+    // least two quads a side so its edges can still be stitched.
+    pub fn levels(&self, resolution: u32) -> u32 {
+        self.levels.min(resolution.max(1).trailing_zeros()).max(1)
+    }
+
+    pub fn resolution(&self, resolution: u32, level: usize) -> u32 {
+        resolution >> level
+    }
+
+    // Picks the level for something `distance` from the camera. With a
+    // current level, the distance has to pass the switching point by the
+    // hysteresis fraction first, so a chunk sitting on a boundary doesn't
+    // flick between levels every frame.
+    pub fn select(&self, resolution: u32, distance: f32, current: Option<usize>) -> usize {
+        let last = self.levels(resolution) as i32 - 1;
+        let level = |distance: f32| {
+            if distance <= self.distance {
+                return 0;
+            }
+            ((distance / self.distance).log2().floor() as i32 + 1).clamp(0, last) as usize
+        };
+        let ideal = level(distance);
+        match current {
+            Some(current) if ideal > current => {
+                level(distance / (1.0 + self.hysteresis)).max(current)
+            }
+            Some(current) if ideal < current => {
+                level(distance * (1.0 + self.hysteresis)).min(current)
+            }
+            _ => ideal,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_double_in_distance() {
+        let lod = LodSettings::default();
+        assert_eq!(lod.select(64, 0.5, None), 0);
+        assert_eq!(lod.select(64, 0.75, None), 0);
+        assert_eq!(lod.select(64, 1.0, None), 1);
+        assert_eq!(lod.select(64, 1.5, None), 2);
+        assert_eq!(lod.select(64, 2.9, None), 2);
+        assert_eq!(lod.select(64, 3.0, None), 3);
+    }
+
+    #[test]
+    fn levels_are_clamped() {
+        let lod = LodSettings::default();
+        assert_eq!(lod.levels(64), 4);
+        assert_eq!(lod.select(64, 100.0, None), 3);
+        // 12 can only be halved twice evenly.
+        assert_eq!(lod.levels(12), 2);
+        assert_eq!(lod.select(12, 100.0, None), 1);
+        assert_eq!(lod.levels(1), 1);
+        assert_eq!(lod.select(1, 100.0, None), 0);
+    }
+
+    #[test]
+    fn hysteresis_delays_coarser_levels() {
+        let lod = LodSettings::default();
+        // Just past the 0.75 boundary isn't far enough to switch yet.
+        assert_eq!(lod.select(64, 0.8, Some(0)), 0);
+        assert_eq!(lod.select(64, 0.9, Some(0)), 1);
+    }
+
+    #[test]
+    fn hysteresis_delays_finer_levels() {
+        let lod = LodSettings::default();
+        assert_eq!(lod.select(64, 0.7, Some(1)), 1);
+        assert_eq!(lod.select(64, 0.6, Some(1)), 0);
+        // Far from the boundary the ideal level is used straight away.
+        assert_eq!(lod.select(64, 0.1, Some(3)), 0);
+    }
+}
@@ -1,10 +1,33 @@
 mod texture;
 mod app;
 mod camera;
+mod chunk;
+mod clipmap;
 mod entity;
+mod erosion;
+mod fractal;
+mod frustum;
+mod glb;
+mod gpu_erosion;
+#[cfg(test)]
+mod gpu_test;
+mod heightfield;
+mod heightmap;
+mod horizon;
 mod light;
+mod lod;
+mod model;
+mod noise;
+mod obj;
+mod ply;
+mod roam;
+mod settings;
+mod shadow;
+mod stl;
 mod vertex;
+mod worker;
 fn main() {
+    env_logger::init();
     match app::run() {
         Ok(_) => {}
         Err(e) => eprintln!("{e}"),
@@ -0,0 +1,152 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::*;
+use cgmath::*;
+
+use crate::{
+    glb, obj,
+    texture::TextureSource,
+    vertex::{Mesh, Vertex},
+};
+
+// CPU side of an imported file: one part per mesh primitive with its node
+// transform, and textures still encoded so they can be exported again.
+#[derive(Clone)]
+pub struct Model {
+    pub parts: Vec<ModelPart>,
+    pub materials: Vec<ModelMaterial>,
+}
+
+#[derive(Clone)]
+pub struct ModelPart {
+    pub name: String,
+    pub mesh: Mesh,
+    pub transform: Matrix4<f32>,
+    pub material: Option<usize>,
+}
+
+#[derive(Clone)]
+pub struct ModelMaterial {
+    pub name: String,
+    pub diffuse: Option<TextureSource>,
+    pub normal: Option<TextureSource>,
+}
+
+pub fn load(path: &Path) -> Result<Model> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("obj") => obj::load(path),
+        Some("gltf") | Some("glb") => glb::load(path),
+        _ => bail!("{} is not an OBJ or glTF file", path.display()),
+    }
+    .with_context(|| format!("unable to load model {}", path.display()))
+}
+
+// Where a model goes on the terrain, given as plane UVs so it doesn't depend on
+// the terrain's world size, and what to multiply its file units by.
+#[derive(Debug, Clone)]
+pub struct ModelPlacement {
+    pub path: PathBuf,
+    pub uv: (f32, f32),
+    pub scale: f32,
+}
+
+// Only an all numeric suffix is a placement, so paths can contain '@' too.
+pub fn parse_placement(s: &str) -> Result<ModelPlacement> {
+    let placement = s.rsplit_once('@').and_then(|(path, at)| {
+        let values = at
+            .split(',')
+            .map(|v| v.parse::<f32>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .ok()?;
+        Some((path, values))
+    });
+    let Some((path, values)) = placement else {
+        return Ok(ModelPlacement {
+            path: s.into(),
+            uv: (0.5, 0.5),
+            scale: 1.0,
+        });
+    };
+    let (uv, scale) = match values[..] {
+        [u, v] => ((u, v), 1.0),
+        [u, v, scale] => ((u, v), scale),
+        _ => bail!("expected FILE@U,V[,SCALE], got {s}"),
+    };
+    Ok(ModelPlacement {
+        path: path.into(),
+        uv,
+        scale,
+    })
+}
+
+pub fn vertex(pos: [f32; 3], normal: [f32; 3], tex_coords: [f32; 2], color: [f32; 4]) -> Vertex {
+    Vertex {
+        pos: [pos[0], pos[1], pos[2], 1.0],
+        color,
+        normal: [normal[0], normal[1], normal[2], 1.0],
+        tex_coords,
+    }
+}
+
+// For files without normals: area weighted vertex normals from the triangles.
+pub fn smooth_normals(vertices: &mut [Vertex], indices: &[u32]) {
+    let mut normals = vec![Vector3::<f32>::zero(); vertices.len()];
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [triangle[0], triangle[1], triangle[2]].map(|i| i as usize);
+        let p = |i: usize| Vector4::from(vertices[i].pos).truncate();
+        let n = (p(b) - p(a)).cross(p(c) - p(a));
+        normals[a] += n;
+        normals[b] += n;
+        normals[c] += n;
+    }
+    for (vertex, n) in vertices.iter_mut().zip(normals) {
+        let n = if n.magnitude2() > 0.0 {
+            n.normalize()
+        } else {
+            Vector3::unit_y()
+        };
+        vertex.normal = n.extend(1.0).into();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placement_defaults_to_the_centre() {
+        let placement = parse_placement("props/rock.obj").unwrap();
+        assert_eq!(placement.path, PathBuf::from("props/rock.obj"));
+        assert_eq!(placement.uv, (0.5, 0.5));
+        assert_eq!(placement.scale, 1.0);
+    }
+
+    #[test]
+    fn placement_reads_uv_and_scale() {
+        let placement = parse_placement("rock.glb@0.25,0.75").unwrap();
+        assert_eq!(placement.path, PathBuf::from("rock.glb"));
+        assert_eq!(placement.uv, (0.25, 0.75));
+        assert_eq!(placement.scale, 1.0);
+        assert_eq!(parse_placement("rock.glb@0.1,0.2,40").unwrap().scale, 40.0);
+    }
+
+    #[test]
+    fn placement_keeps_at_signs_in_paths() {
+        for path in ["assets@2x/rock.obj", "rock@2x.obj", "a@b/tree.glb"] {
+            assert_eq!(parse_placement(path).unwrap().path, PathBuf::from(path));
+        }
+        let placement = parse_placement("rock@2x.obj@0.1,0.9").unwrap();
+        assert_eq!(placement.path, PathBuf::from("rock@2x.obj"));
+        assert_eq!(placement.uv, (0.1, 0.9));
+    }
+
+    #[test]
+    fn placement_rejects_the_wrong_number_of_values() {
+        assert!(parse_placement("rock.obj@0.5").is_err());
+        assert!(parse_placement("rock.obj@1,2,3,4").is_err());
+    }
+}
@@ -0,0 +1,454 @@
+use std::str::FromStr;
+
+use anyhow::*;
+
+// splitmix64, so a seed produces the same sequence on every platform.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoiseKind {
+    Perlin,
+    Simplex,
+    Value,
+}
+
+impl FromStr for NoiseKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "perlin" => Ok(Self::Perlin),
+            "simplex" => Ok(Self::Simplex),
+            "value" => Ok(Self::Value),
+            _ => bail!("unknown noise kind {s}, expected perlin, simplex or value"),
+        }
+    }
+}
+
+// Keep in sync with shaders/noise.wgsl; wgsl_matches_cpu checks that they agree.
+#[derive(Debug, Clone)]
+pub struct Noise {
+    perm: [u8; 512],
+}
+
+const F2: f32 = 0.3660254; // (sqrt(3) - 1) / 2
+const G2: f32 = 0.21132487; // (3 - sqrt(3)) / 6
+
+impl Noise {
+    pub fn new(seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        let mut table: [u8; 256] = std::array::from_fn(|i| i as u8);
+        for i in (1..256).rev() {
+            let j = (rng.next_u64() % (i as u64 + 1)) as usize;
+            table.swap(i, j);
+        }
+        let perm = std::array::from_fn(|i| table[i & 255]);
+        Self { perm }
+    }
+
+    pub fn sample2(&self, kind: NoiseKind, x: f32, y: f32) -> f32 {
+        match kind {
+            NoiseKind::Perlin => self.perlin2(x, y),
+            NoiseKind::Simplex => self.simplex2(x, y),
+            NoiseKind::Value => self.value2(x, y),
+        }
+    }
+
+    fn hash2(&self, x: i32, y: i32) -> u8 {
+        self.perm[self.perm[(x & 255) as usize] as usize + (y & 255) as usize]
+    }
+
+    pub fn perlin2(&self, x: f32, y: f32) -> f32 {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (xf, yf) = (x - x0, y - y0);
+        let (xi, yi) = (x0 as i32, y0 as i32);
+        let (u, v) = (fade(xf), fade(yf));
+
+        let n00 = grad2(self.hash2(xi, yi), xf, yf);
+        let n10 = grad2(self.hash2(xi + 1, yi), xf - 1.0, yf);
+        let n01 = grad2(self.hash2(xi, yi + 1), xf, yf - 1.0);
+        let n11 = grad2(self.hash2(xi + 1, yi + 1), xf - 1.0, yf - 1.0);
+        lerp(lerp(n00, n10, u), lerp(n01, n11, u), v)
+    }
+
+    pub fn simplex2(&self, x: f32, y: f32) -> f32 {
+        let s = (x + y) * F2;
+        let (i, j) = ((x + s).floor(), (y + s).floor());
+        let t = (i + j) * G2;
+        let x0 = x - (i - t);
+        let y0 = y - (j - t);
+        let (i1, j1) = if x0 > y0 { (1, 0) } else { (0, 1) };
+        let x1 = x0 - i1 as f32 + G2;
+        let y1 = y0 - j1 as f32 + G2;
+        let x2 = x0 - 1.0 + 2.0 * G2;
+        let y2 = y0 - 1.0 + 2.0 * G2;
+        let (i, j) = (i as i32, j as i32);
+
+        let corner = |h: u8, x: f32, y: f32| {
+            let t = 0.5 - x * x - y * y;
+            if t < 0.0 {
+                0.0
+            } else {
+                t * t * t * t * grad2(h, x, y)
+            }
+        };
+        let n0 = corner(self.hash2(i, j), x0, y0);
+        let n1 = corner(self.hash2(i + i1, j + j1), x1, y1);
+        let n2 = corner(self.hash2(i + 1, j + 1), x2, y2);
+        70.0 * (n0 + n1 + n2)
+    }
+
+    pub fn value2(&self, x: f32, y: f32) -> f32 {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (u, v) = (fade(x - x0), fade(y - y0));
+        let (xi, yi) = (x0 as i32, y0 as i32);
+
+        let n00 = value(self.hash2(xi, yi));
+        let n10 = value(self.hash2(xi + 1, yi));
+        let n01 = value(self.hash2(xi, yi + 1));
+        let n11 = value(self.hash2(xi + 1, yi + 1));
+        lerp(lerp(n00, n10, u), lerp(n01, n11, u), v)
+    }
+}
+
+// Nothing samples volumes yet; the tests keep these in step with noise.wgsl.
+#[allow(dead_code)]
+impl Noise {
+    const F3: f32 = 1.0 / 3.0;
+    const G3: f32 = 1.0 / 6.0;
+
+    pub fn sample3(&self, kind: NoiseKind, x: f32, y: f32, z: f32) -> f32 {
+        match kind {
+            NoiseKind::Perlin => self.perlin3(x, y, z),
+            NoiseKind::Simplex => self.simplex3(x, y, z),
+            NoiseKind::Value => self.value3(x, y, z),
+        }
+    }
+
+    fn hash3(&self, x: i32, y: i32, z: i32) -> u8 {
+        self.perm[self.hash2(x, y) as usize + (z & 255) as usize]
+    }
+
+    pub fn perlin3(&self, x: f32, y: f32, z: f32) -> f32 {
+        let (x0, y0, z0) = (x.floor(), y.floor(), z.floor());
+        let (xf, yf, zf) = (x - x0, y - y0, z - z0);
+        let (xi, yi, zi) = (x0 as i32, y0 as i32, z0 as i32);
+        let (u, v, w) = (fade(xf), fade(yf), fade(zf));
+
+        let n000 = Self::grad3(self.hash3(xi, yi, zi), xf, yf, zf);
+        let n100 = Self::grad3(self.hash3(xi + 1, yi, zi), xf - 1.0, yf, zf);
+        let n010 = Self::grad3(self.hash3(xi, yi + 1, zi), xf, yf - 1.0, zf);
+        let n110 = Self::grad3(self.hash3(xi + 1, yi + 1, zi), xf - 1.0, yf - 1.0, zf);
+        let n001 = Self::grad3(self.hash3(xi, yi, zi + 1), xf, yf, zf - 1.0);
+        let n101 = Self::grad3(self.hash3(xi + 1, yi, zi + 1), xf - 1.0, yf, zf - 1.0);
+        let n011 = Self::grad3(self.hash3(xi, yi + 1, zi + 1), xf, yf - 1.0, zf - 1.0);
+        let n111 = Self::grad3(
+            self.hash3(xi + 1, yi + 1, zi + 1),
+            xf - 1.0,
+            yf - 1.0,
+            zf - 1.0,
+        );
+        let nx00 = lerp(n000, n100, u);
+        let nx10 = lerp(n010, n110, u);
+        let nx01 = lerp(n001, n101, u);
+        let nx11 = lerp(n011, n111, u);
+        lerp(lerp(nx00, nx10, v), lerp(nx01, nx11, v), w)
+    }
+
+    pub fn simplex3(&self, x: f32, y: f32, z: f32) -> f32 {
+        let s = (x + y + z) * Self::F3;
+        let (i, j, k) = ((x + s).floor(), (y + s).floor(), (z + s).floor());
+        let t = (i + j + k) * Self::G3;
+        let x0 = x - (i - t);
+        let y0 = y - (j - t);
+        let z0 = z - (k - t);
+        let (i1, j1, k1, i2, j2, k2) = if x0 >= y0 {
+            if y0 >= z0 {
+                (1, 0, 0, 1, 1, 0)
+            } else if x0 >= z0 {
+                (1, 0, 0, 1, 0, 1)
+            } else {
+                (0, 0, 1, 1, 0, 1)
+            }
+        } else if y0 < z0 {
+            (0, 0, 1, 0, 1, 1)
+        } else if x0 < z0 {
+            (0, 1, 0, 0, 1, 1)
+        } else {
+            (0, 1, 0, 1, 1, 0)
+        };
+        let x1 = x0 - i1 as f32 + Self::G3;
+        let y1 = y0 - j1 as f32 + Self::G3;
+        let z1 = z0 - k1 as f32 + Self::G3;
+        let x2 = x0 - i2 as f32 + 2.0 * Self::G3;
+        let y2 = y0 - j2 as f32 + 2.0 * Self::G3;
+        let z2 = z0 - k2 as f32 + 2.0 * Self::G3;
+        let x3 = x0 - 1.0 + 3.0 * Self::G3;
+        let y3 = y0 - 1.0 + 3.0 * Self::G3;
+        let z3 = z0 - 1.0 + 3.0 * Self::G3;
+        let (i, j, k) = (i as i32, j as i32, k as i32);
+
+        let corner = |h: u8, x: f32, y: f32, z: f32| {
+            let t = 0.6 - x * x - y * y - z * z;
+            if t < 0.0 {
+                0.0
+            } else {
+                t * t * t * t * Self::grad3(h, x, y, z)
+            }
+        };
+        let n0 = corner(self.hash3(i, j, k), x0, y0, z0);
+        let n1 = corner(self.hash3(i + i1, j + j1, k + k1), x1, y1, z1);
+        let n2 = corner(self.hash3(i + i2, j + j2, k + k2), x2, y2, z2);
+        let n3 = corner(self.hash3(i + 1, j + 1, k + 1), x3, y3, z3);
+        32.0 * (n0 + n1 + n2 + n3)
+    }
+
+    pub fn value3(&self, x: f32, y: f32, z: f32) -> f32 {
+        let (x0, y0, z0) = (x.floor(), y.floor(), z.floor());
+        let (u, v, w) = (fade(x - x0), fade(y - y0), fade(z - z0));
+        let (xi, yi, zi) = (x0 as i32, y0 as i32, z0 as i32);
+
+        let nx00 = lerp(
+            value(self.hash3(xi, yi, zi)),
+            value(self.hash3(xi + 1, yi, zi)),
+            u,
+        );
+        let nx10 = lerp(
+            value(self.hash3(xi, yi + 1, zi)),
+            value(self.hash3(xi + 1, yi + 1, zi)),
+            u,
+        );
+        let nx01 = lerp(
+            value(self.hash3(xi, yi, zi + 1)),
+            value(self.hash3(xi + 1, yi, zi + 1)),
+            u,
+        );
+        let nx11 = lerp(
+            value(self.hash3(xi, yi + 1, zi + 1)),
+            value(self.hash3(xi + 1, yi + 1, zi + 1)),
+            u,
+        );
+        lerp(lerp(nx00, nx10, v), lerp(nx01, nx11, v), w)
+    }
+
+    fn grad3(h: u8, x: f32, y: f32, z: f32) -> f32 {
+        match h & 15 {
+            0 | 12 => x + y,
+            1 | 14 => -x + y,
+            2 => x - y,
+            3 => -x - y,
+            4 => x + z,
+            5 => -x + z,
+            6 => x - z,
+            7 => -x - z,
+            8 => y + z,
+            9 | 13 => -y + z,
+            10 => y - z,
+            _ => -y - z,
+        }
+    }
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn value(h: u8) -> f32 {
+    h as f32 / 127.5 - 1.0
+}
+
+fn grad2(h: u8, x: f32, y: f32) -> f32 {
+    match h & 7 {
+        0 => x + y,
+        1 => -x + y,
+        2 => x - y,
+        3 => -x - y,
+        4 => x,
+        5 => -x,
+        6 => y,
+        _ => -y,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wgpu::util::DeviceExt;
+
+    use super::*;
+    use crate::gpu_test;
+
+    const KINDS: [NoiseKind; 3] = [NoiseKind::Perlin, NoiseKind::Simplex, NoiseKind::Value];
+
+    // Points off the lattice, on both sides of zero and past the table's wrap.
+    fn points() -> Vec<[f32; 3]> {
+        (0..64)
+            .map(|i| {
+                let i = i as f32;
+                [i * 4.37 - 130.0, i * -2.91 + 40.0, i * 1.13 - 7.5]
+            })
+            .collect()
+    }
+
+    // How noise.wgsl expects the table: four entries to each vec4<u32>.
+    fn perm_uniform_data(noise: &Noise) -> [[u32; 4]; 64] {
+        std::array::from_fn(|i| std::array::from_fn(|j| noise.perm[4 * i + j] as u32))
+    }
+
+    fn sample_all(noise: &Noise) -> Vec<f32> {
+        KINDS
+            .iter()
+            .flat_map(|&kind| {
+                points().into_iter().flat_map(move |[x, y, z]| {
+                    [noise.sample2(kind, x, y), noise.sample3(kind, x, y, z)]
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_gives_same_noise() {
+        let first = sample_all(&Noise::new(42));
+        assert_eq!(first, sample_all(&Noise::new(42)));
+        assert_ne!(first, sample_all(&Noise::new(43)));
+    }
+
+    #[test]
+    fn perlin_is_zero_on_the_lattice() {
+        let noise = Noise::new(7);
+        for (x, y) in [(0, 0), (3, -5), (-200, 311)] {
+            assert_eq!(noise.perlin2(x as f32, y as f32), 0.0);
+            assert_eq!(noise.perlin3(x as f32, y as f32, 4.0), 0.0);
+        }
+    }
+
+    #[test]
+    fn wgsl_matches_cpu() {
+        let source = format!(
+            "{}\n{}",
+            include_str!("shaders/noise.wgsl"),
+            r#"
+@group(0) @binding(0)
+var<uniform> noise_perm: array<vec4<u32>, 64>;
+@group(0) @binding(1)
+var<storage, read> points: array<vec4<f32>>;
+@group(0) @binding(2)
+var<storage, read_write> results: array<f32>;
+
+@compute @workgroup_size(64)
+fn cs_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let count = arrayLength(&points);
+    if id.x >= count {
+        return;
+    }
+    let p = points[id.x];
+    for (var kind = 0u; kind < 3u; kind++) {
+        let i = 2u * (kind * count + id.x);
+        results[i] = noise_sample2(kind, p.xy);
+        results[i + 1u] = noise_sample3(kind, p.xyz);
+    }
+}
+"#
+        );
+        let module = wgpu::naga::front::wgsl::parse_str(&source).unwrap();
+        wgpu::naga::valid::Validator::new(
+            wgpu::naga::valid::ValidationFlags::all(),
+            wgpu::naga::valid::Capabilities::empty(),
+        )
+        .validate(&module)
+        .unwrap();
+
+        let Some((device, queue)) = gpu_test::fallback_device() else {
+            eprintln!("no fallback adapter, skipping the GPU comparison");
+            return;
+        };
+        let noise = Noise::new(1234);
+        let points = points()
+            .into_iter()
+            .map(|[x, y, z]| [x, y, z, 0.0])
+            .collect::<Vec<_>>();
+        let perm = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&perm_uniform_data(&noise)),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let points_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&points),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let results = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: (4 * 2 * KINDS.len() * points.len()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: None,
+            layout: None,
+            module: &shader,
+            entry_point: Some("cs_main"),
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            cache: None,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: perm.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: points_buf.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: results.as_entire_binding(),
+                },
+            ],
+        });
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor::default());
+            pass.set_pipeline(&pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups((points.len() as u32).div_ceil(64), 1, 1);
+        }
+        queue.submit(Some(encoder.finish()));
+
+        let gpu = gpu_test::read_buffer(&device, &queue, &results);
+        let gpu: &[f32] = bytemuck::cast_slice(&gpu);
+        for (i, (gpu, cpu)) in gpu.iter().zip(sample_all(&noise)).enumerate() {
+            assert!((gpu - cpu).abs() < 1e-4, "sample {i}: GPU {gpu}, CPU {cpu}");
+        }
+    }
+}
@@ -0,0 +1,400 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::*;
+use cgmath::*;
+
+use crate::{
+    entity::Entity,
+    model::{self, Model, ModelMaterial, ModelPart},
+    texture::{Texture, TextureSource},
+    vertex::{self, Material, Mesh, Vertex},
+};
+
+// Vertices are written in world space (y up) so Blender's default OBJ import
+// axes place the terrain the same way the app does.
+pub fn save(path: &Path, name: &str, entity: &Entity, material: Option<&Material>) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "# terrain-gen")?;
+    if let Some(material) = material {
+        let mtl_path = path.with_extension("mtl");
+        save_mtl(&mtl_path, material)?;
+        writeln!(out, "mtllib {}", file_name(&mtl_path)?)?;
+    }
+    writeln!(out, "o {name}")?;
+
+    let model = entity.get_model_matrix();
+    let normal_matrix = vertex::normal_matrix(model);
+    let mesh = entity.mesh();
+    for vertex in mesh.vertices() {
+        let p = model * Vector4::from(vertex.pos);
+        writeln!(out, "v {} {} {}", p.x, p.y, p.z)?;
+    }
+    for vertex in mesh.vertices() {
+        let [u, v] = vertex.tex_coords;
+        writeln!(out, "vt {} {}", u, 1.0 - v)?;
+    }
+    for vertex in mesh.vertices() {
+        let n = (normal_matrix * Vector4::from(vertex.normal).truncate()).normalize();
+        writeln!(out, "vn {} {} {}", n.x, n.y, n.z)?;
+    }
+
+    if let Some(material) = material {
+        writeln!(out, "usemtl {}", material.name)?;
+    }
+    for triangle in mesh.indices().chunks_exact(3) {
+        let [a, b, c] = [triangle[0] + 1, triangle[1] + 1, triangle[2] + 1];
+        writeln!(out, "f {a}/{a}/{a} {b}/{b}/{b} {c}/{c}/{c}")?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn save_mtl(path: &Path, material: &Material) -> Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "newmtl {}", material.name)?;
+    writeln!(out, "Ka 1.0 1.0 1.0")?;
+    writeln!(out, "Kd 1.0 1.0 1.0")?;
+    writeln!(out, "Ks 0.5 0.5 0.5")?;
+    writeln!(out, "Ns 32.0")?;
+    if let Some(name) = save_texture(dir, &material.diffuse_texture)? {
+        writeln!(out, "map_Kd {name}")?;
+    }
+    if let Some(name) = save_texture(dir, &material.normal_texture)? {
+        // `map_Bump` would be read as a height map.
+        writeln!(out, "norm {name}")?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn save_texture<'a>(dir: &Path, texture: &'a Texture) -> Result<Option<&'a str>> {
+    let Some(source) = &texture.source else {
+        return Ok(None);
+    };
+    std::fs::write(dir.join(&source.name), &source.bytes)?;
+    Ok(Some(&source.name))
+}
+
+fn file_name(path: &Path) -> Result<&str> {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .with_context(|| format!("{} has no file name", path.display()))
+}
+
+// Faces are fanned into triangles and split into one part per usemtl, since a
+// part draws with a single material.
+pub fn load(path: &Path) -> Result<Model> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let text = std::fs::read_to_string(path)?;
+    let mut positions: Vec<[f32; 3]> = vec![];
+    let mut tex_coords: Vec<[f32; 2]> = vec![];
+    let mut normals: Vec<[f32; 3]> = vec![];
+    let mut materials: Vec<ModelMaterial> = vec![];
+    let mut material_colors: Vec<[f32; 4]> = vec![];
+    let mut parts: Vec<ObjPart> = vec![];
+    let mut object = path
+        .file_stem()
+        .and_then(|n| n.to_str())
+        .unwrap_or("model")
+        .to_string();
+    let mut material = None;
+
+    for (line_number, line) in text.lines().enumerate() {
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+        let rest: Vec<&str> = tokens.collect();
+        let floats = || -> Result<Vec<f32>> {
+            rest.iter()
+                .map(|t| t.parse::<f32>())
+                .collect::<std::result::Result<_, _>>()
+                .with_context(|| format!("line {}: bad number", line_number + 1))
+        };
+        match keyword {
+            "v" => {
+                let v = floats()?;
+                ensure!(v.len() >= 3, "line {}: vertex needs x y z", line_number + 1);
+                positions.push([v[0], v[1], v[2]]);
+            }
+            "vt" => {
+                let v = floats()?;
+                ensure!(!v.is_empty(), "line {}: vt needs u", line_number + 1);
+                tex_coords.push([v[0], 1.0 - v.get(1).copied().unwrap_or(0.0)]);
+            }
+            "vn" => {
+                let v = floats()?;
+                ensure!(v.len() >= 3, "line {}: vn needs x y z", line_number + 1);
+                normals.push([v[0], v[1], v[2]]);
+            }
+            "o" | "g" => {
+                if let Some(name) = rest.first() {
+                    object = name.to_string();
+                }
+            }
+            "mtllib" => {
+                for name in &rest {
+                    // Faces using a material that never got loaded draw with the
+                    // default one instead.
+                    let mtl = dir.join(name);
+                    if !mtl.exists() {
+                        log::warn!("{} is missing, using the default material", mtl.display());
+                        continue;
+                    }
+                    load_mtl(&mtl, &mut materials, &mut material_colors)?;
+                }
+            }
+            "usemtl" => {
+                material = rest
+                    .first()
+                    .and_then(|name| materials.iter().position(|m| m.name == *name));
+            }
+            "f" => {
+                let corners = rest
+                    .iter()
+                    .map(|corner| {
+                        parse_corner(corner, positions.len(), tex_coords.len(), normals.len())
+                    })
+                    .collect::<Result<Vec<_>>>()
+                    .with_context(|| format!("line {}: bad face", line_number + 1))?;
+                let part = match parts
+                    .iter_mut()
+                    .position(|p| p.name == object && p.material == material)
+                {
+                    Some(index) => &mut parts[index],
+                    None => {
+                        parts.push(ObjPart::new(&object, material));
+                        parts.last_mut().unwrap()
+                    }
+                };
+                let color = material
+                    .map(|index| material_colors[index])
+                    .unwrap_or([1.0; 4]);
+                let indices = corners
+                    .iter()
+                    .map(|&corner| part.index(corner, &positions, &tex_coords, &normals, color))
+                    .collect::<Vec<_>>();
+                for i in 1..indices.len().saturating_sub(1) {
+                    part.indices
+                        .extend([indices[0], indices[i], indices[i + 1]]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let parts = parts
+        .into_iter()
+        .map(|mut part| {
+            if !part.has_normals {
+                model::smooth_normals(&mut part.vertices, &part.indices);
+            }
+            ModelPart {
+                name: part.name,
+                mesh: Mesh::new(part.vertices, part.indices),
+                transform: Matrix4::identity(),
+                material: part.material,
+            }
+        })
+        .collect();
+    Ok(Model { parts, materials })
+}
+
+type Corner = (usize, Option<usize>, Option<usize>);
+
+struct ObjPart {
+    name: String,
+    material: Option<usize>,
+    vertices: Vec<Vertex>,
+    indices: Vec<u32>,
+    corners: HashMap<Corner, u32>,
+    has_normals: bool,
+}
+
+impl ObjPart {
+    fn new(name: &str, material: Option<usize>) -> Self {
+        Self {
+            name: String::from(name),
+            material,
+            vertices: vec![],
+            indices: vec![],
+            corners: HashMap::new(),
+            has_normals: true,
+        }
+    }
+
+    fn index(
+        &mut self,
+        corner: Corner,
+        positions: &[[f32; 3]],
+        tex_coords: &[[f32; 2]],
+        normals: &[[f32; 3]],
+        color: [f32; 4],
+    ) -> u32 {
+        if let Some(&index) = self.corners.get(&corner) {
+            return index;
+        }
+        let (v, vt, vn) = corner;
+        self.has_normals &= vn.is_some();
+        self.vertices.push(model::vertex(
+            positions[v],
+            vn.map(|i| normals[i]).unwrap_or([0.0, 1.0, 0.0]),
+            vt.map(|i| tex_coords[i]).unwrap_or([0.0, 0.0]),
+            color,
+        ));
+        let index = self.vertices.len() as u32 - 1;
+        self.corners.insert(corner, index);
+        index
+    }
+}
+
+// v, v/vt, v//vn or v/vt/vn, 1-based or negative from the end of each list.
+fn parse_corner(
+    corner: &str,
+    positions: usize,
+    tex_coords: usize,
+    normals: usize,
+) -> Result<Corner> {
+    let resolve = |index: &str, len: usize| -> Result<Option<usize>> {
+        if index.is_empty() {
+            return Ok(None);
+        }
+        let index: i64 = index.parse()?;
+        let resolved = if index < 0 {
+            len as i64 + index
+        } else {
+            index - 1
+        };
+        ensure!(
+            (0..len as i64).contains(&resolved),
+            "index {index} out of range"
+        );
+        Ok(Some(resolved as usize))
+    };
+    let mut fields = corner.split('/');
+    let v =
+        resolve(fields.next().unwrap_or(""), positions)?.context("face corner has no vertex")?;
+    let vt = resolve(fields.next().unwrap_or(""), tex_coords)?;
+    let vn = resolve(fields.next().unwrap_or(""), normals)?;
+    Ok((v, vt, vn))
+}
+
+fn load_mtl(
+    path: &Path,
+    materials: &mut Vec<ModelMaterial>,
+    colors: &mut Vec<[f32; 4]>,
+) -> Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("unable to read {}", path.display()))?;
+    for line in text.lines() {
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+        // Texture options come before the file name, so the name is the last token.
+        let last = tokens.clone().last();
+        match (keyword, materials.last_mut(), colors.last_mut()) {
+            ("newmtl", _, _) => {
+                materials.push(ModelMaterial {
+                    name: tokens.next().unwrap_or_default().to_string(),
+                    diffuse: None,
+                    normal: None,
+                });
+                colors.push([1.0; 4]);
+            }
+            ("Kd", Some(_), Some(color)) => {
+                for (c, token) in color.iter_mut().zip(tokens) {
+                    *c = token.parse()?;
+                }
+            }
+            ("map_Kd", Some(material), _) => {
+                material.diffuse = last.map(|name| read_texture(dir, name)).transpose()?;
+            }
+            ("norm" | "map_Bump" | "map_bump" | "bump", Some(material), _) => {
+                material.normal = last.map(|name| read_texture(dir, name)).transpose()?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn read_texture(dir: &Path, name: &str) -> Result<TextureSource> {
+    let path = dir.join(name);
+    let bytes =
+        std::fs::read(&path).with_context(|| format!("unable to read {}", path.display()))?;
+    Ok(TextureSource {
+        name: file_name(&path)?.to_string(),
+        bytes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_mtl_falls_back_to_the_default_material() {
+        let dir = std::env::temp_dir().join(format!("terrain-gen-obj-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("quad.obj");
+        std::fs::write(
+            &path,
+            "mtllib missing.mtl\nv 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nusemtl stone\nf 1 2 3 4\n",
+        )
+        .unwrap();
+        let model = load(&path);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let model = model.unwrap();
+        assert!(model.materials.is_empty());
+        assert_eq!(model.parts.len(), 1);
+        assert_eq!(model.parts[0].material, None);
+        assert_eq!(model.parts[0].mesh.indices().len(), 6);
+    }
+
+    #[test]
+    fn exported_normals_follow_non_uniform_scale() {
+        let normal = vec3(1.0f32, 1.0, 0.0).normalize();
+        let vertex = |pos: [f32; 3]| Vertex {
+            pos: [pos[0], pos[1], pos[2], 1.0],
+            color: [1.0; 4],
+            normal: normal.extend(1.0).into(),
+            tex_coords: [0.0; 2],
+        };
+        let mesh = Mesh::new(
+            vec![
+                vertex([1.0, 0.0, 0.0]),
+                vertex([0.0, 1.0, 0.0]),
+                vertex([1.0, 0.0, 1.0]),
+            ],
+            vec![0, 1, 2],
+        );
+        let entity = Entity::new(
+            mesh,
+            vec3(0.0, 2.0, 0.0),
+            Quaternion::from_angle_z(Deg(20.0)),
+            vec3(1.0, 4.0, 1.0),
+        );
+        let dir = std::env::temp_dir().join(format!("terrain-gen-obj-save-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("triangle.obj");
+        save(&path, "triangle", &entity, None).unwrap();
+        let model = load(&path);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mesh = &model.unwrap().parts[0].mesh;
+        let position = |i: usize| Vector4::from(mesh.vertices()[i].pos).truncate();
+        let normal = Vector4::from(mesh.vertices()[0].normal).truncate();
+        assert!((normal.magnitude() - 1.0).abs() < 1e-4);
+        assert!(normal.dot(position(1) - position(0)).abs() < 1e-4);
+        assert!(normal.dot(position(2) - position(0)).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,65 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::*;
+use cgmath::*;
+
+use crate::{entity::Entity, vertex};
+
+// Binary little endian PLY in world space (y up, like the OBJ export) with
+// normals, UVs and the vertex colors.
+pub fn save(path: &Path, entity: &Entity) -> Result<()> {
+    let model = entity.get_model_matrix();
+    let normal_matrix = vertex::normal_matrix(model);
+    let mesh = entity.mesh();
+    let mut out = BufWriter::new(File::create(path)?);
+    write!(
+        out,
+        "ply\n\
+         format binary_little_endian 1.0\n\
+         comment terrain-gen\n\
+         element vertex {}\n\
+         property float x\n\
+         property float y\n\
+         property float z\n\
+         property float nx\n\
+         property float ny\n\
+         property float nz\n\
+         property float s\n\
+         property float t\n\
+         property uchar red\n\
+         property uchar green\n\
+         property uchar blue\n\
+         property uchar alpha\n\
+         element face {}\n\
+         property list uchar uint vertex_indices\n\
+         end_header\n",
+        mesh.vertices().len(),
+        mesh.indices().len() / 3,
+    )?;
+
+    for vertex in mesh.vertices() {
+        let p = (model * Vector4::from(vertex.pos)).truncate();
+        let n = (normal_matrix * Vector4::from(vertex.normal).truncate()).normalize();
+        let [u, v] = vertex.tex_coords;
+        for value in [p.x, p.y, p.z, n.x, n.y, n.z, u, 1.0 - v] {
+            out.write_all(&value.to_le_bytes())?;
+        }
+        out.write_all(
+            &vertex
+                .color
+                .map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8),
+        )?;
+    }
+    for triangle in mesh.indices().chunks_exact(3) {
+        out.write_all(&[3])?;
+        for index in triangle {
+            out.write_all(&index.to_le_bytes())?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
@@ -0,0 +1,335 @@
+use cgmath::*;
+use wgpu::util::DeviceExt;
+
+use crate::{entity::Entity, vertex};
+
+#[derive(Debug, Clone)]
+pub struct RoamSettings {
+    // Largest height error allowed on screen, in pixels.
+    pub tolerance: f32,
+    // How far the camera moves, in world units, before the mesh is refined again.
+    pub move_threshold: f32,
+}
+
+impl Default for RoamSettings {
+    fn default() -> Self {
+        Self {
+            tolerance: 2.0,
+            move_threshold: 0.02,
+        }
+    }
+}
+
+type Corner = (u32, u32);
+
+// A bintree triangle on the grid, with its right angle at `apex`.
+#[derive(Debug, Clone, Copy)]
+struct Triangle {
+    apex: Corner,
+    left: Corner,
+    right: Corner,
+}
+
+impl Triangle {
+    // Halves the triangle through the middle of its hypotenuse. None once
+    // there's no grid point there.
+    fn split(&self) -> Option<(Corner, [Triangle; 2])> {
+        let (l, r) = (self.left, self.right);
+        if (l.0 + r.0) % 2 != 0 || (l.1 + r.1) % 2 != 0 {
+            return None;
+        }
+        let mid = ((l.0 + r.0) / 2, (l.1 + r.1) / 2);
+        Some((
+            mid,
+            [
+                Triangle {
+                    apex: mid,
+                    left: self.apex,
+                    right: l,
+                },
+                Triangle {
+                    apex: mid,
+                    left: r,
+                    right: self.apex,
+                },
+            ],
+        ))
+    }
+}
+
+// Triangulates the terrain on the CPU for backends without tessellation. The
+// grid is split as a ROAM style bintree, top down, wherever the vertex in the
+// middle of a hypotenuse would move by more than the tolerance on screen. Each
+// vertex's error and bounding radius are saturated over everything below it
+// (Lindstrom and Pascucci), so both triangles beside a hypotenuse always agree
+// on splitting it and the mesh has no cracks without tracking neighbours.
+pub struct RoamTerrain {
+    settings: RoamSettings,
+    resolution: u32,
+    positions: Vec<Point3<f32>>,
+    errors: Vec<f32>,
+    radii: Vec<f32>,
+    vertex_buf: wgpu::Buffer,
+    index_buf: wgpu::Buffer,
+    index_len: u32,
+    instance_buf: wgpu::Buffer,
+    refined_at: Option<Vector3<f32>>,
+}
+
+impl RoamTerrain {
+    // The terrain's mesh has to be a generate_plane grid with a power of two
+    // resolution; it's uploaded once and only the indices change.
+    pub fn new(device: &wgpu::Device, terrain: &Entity, settings: RoamSettings) -> Self {
+        let vertices = terrain.mesh().vertices();
+        let resolution = (vertices.len() as f64).sqrt() as u32 - 1;
+        assert!(
+            resolution.is_power_of_two() && vertices.len() as u32 == (resolution + 1).pow(2),
+            "ROAM needs a square power of two grid"
+        );
+        let model = terrain.get_model_matrix();
+        let positions = vertices
+            .iter()
+            .map(|v| model.transform_point(Point3::from_homogeneous(v.pos.into())))
+            .collect();
+        let vertex_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("roam vertices"),
+            contents: bytemuck::cast_slice(vertices),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        let index_buf = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("roam indices"),
+            size: 1 << 16,
+            usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let instance_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("roam entity data"),
+            contents: bytemuck::bytes_of(&terrain.to_entity_data()),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+
+        let mut roam = Self {
+            settings,
+            resolution,
+            positions,
+            errors: vec![0.0; vertices.len()],
+            radii: vec![0.0; vertices.len()],
+            vertex_buf,
+            index_buf,
+            index_len: 0,
+            instance_buf,
+            refined_at: None,
+        };
+        roam.saturate();
+        roam
+    }
+
+    // For when the heights change, e.g. after erosion.
+    pub fn set_terrain(&mut self, device: &wgpu::Device, terrain: &Entity) {
+        *self = Self::new(device, terrain, self.settings.clone());
+    }
+
+    pub fn vertex_buf(&self) -> &wgpu::Buffer {
+        &self.vertex_buf
+    }
+
+    pub fn index_buf(&self) -> &wgpu::Buffer {
+        &self.index_buf
+    }
+
+    pub fn index_len(&self) -> u32 {
+        self.index_len
+    }
+
+    pub fn instance_buf(&self) -> &wgpu::Buffer {
+        &self.instance_buf
+    }
+
+    // Refines the mesh again once the camera has moved far enough.
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        camera_position: Vector3<f32>,
+        projection: Matrix4<f32>,
+        viewport_height: f32,
+    ) {
+        if let Some(refined_at) = self.refined_at
+            && (camera_position - refined_at).magnitude() < self.settings.move_threshold
+        {
+            return;
+        }
+        // Pixels covered by one world unit one unit in front of the camera.
+        let pixels_per_unit = projection.y.y * viewport_height * 0.5;
+        let indices = self.refine(Point3::from_vec(camera_position), pixels_per_unit);
+        let bytes: &[u8] = bytemuck::cast_slice(&indices);
+        if (bytes.len() as wgpu::BufferAddress) > self.index_buf.size() {
+            self.index_buf = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("roam indices"),
+                size: (bytes.len() as wgpu::BufferAddress).next_power_of_two(),
+                usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+        }
+        queue.write_buffer(&self.index_buf, 0, bytes);
+        self.index_len = indices.len() as u32;
+        self.refined_at = Some(camera_position);
+    }
+
+    pub fn refine(&self, camera_position: Point3<f32>, pixels_per_unit: f32) -> Vec<u32> {
+        let mut indices = vec![];
+        for root in self.roots() {
+            self.refine_triangle(root, camera_position, pixels_per_unit, &mut indices);
+        }
+        indices
+    }
+
+    fn refine_triangle(
+        &self,
+        triangle: Triangle,
+        camera_position: Point3<f32>,
+        pixels_per_unit: f32,
+        indices: &mut Vec<u32>,
+    ) {
+        if let Some((mid, children)) = triangle.split()
+            && self.active(mid, camera_position, pixels_per_unit)
+        {
+            for child in children {
+                self.refine_triangle(child, camera_position, pixels_per_unit, indices);
+            }
+            return;
+        }
+        let corners = [triangle.left, triangle.right, triangle.apex];
+        indices.extend(vertex::counter_clockwise(corners).map(|c| self.index(c)));
+    }
+
+    // Whether the vertex, or anything refined below it, would be off by more
+    // than the tolerance on screen if it were left out.
+    fn active(&self, corner: Corner, camera_position: Point3<f32>, pixels_per_unit: f32) -> bool {
+        let i = self.index(corner) as usize;
+        let distance = (self.positions[i] - camera_position).magnitude() - self.radii[i];
+        distance <= 0.0 || self.errors[i] * pixels_per_unit > self.settings.tolerance * distance
+    }
+
+    fn roots(&self) -> [Triangle; 2] {
+        let n = self.resolution;
+        [
+            Triangle {
+                apex: (n, 0),
+                left: (n, n),
+                right: (0, 0),
+            },
+            Triangle {
+                apex: (0, n),
+                left: (0, 0),
+                right: (n, n),
+            },
+        ]
+    }
+
+    // Same layout as Mesh::generate_plane.
+    fn index(&self, (u, v): Corner) -> u32 {
+        u * (self.resolution + 1) + v
+    }
+
+    // Works up from the finest level, since a vertex's saturated error needs
+    // the final errors of the vertices split off below it on both sides.
+    fn saturate(&mut self) {
+        let leaf_depth = 2 * self.resolution.trailing_zeros();
+        for depth in (0..leaf_depth).rev() {
+            for root in self.roots() {
+                self.saturate_level(root, 0, depth);
+            }
+        }
+    }
+
+    fn saturate_level(&mut self, triangle: Triangle, depth: u32, target: u32) {
+        let Some((mid, children)) = triangle.split() else {
+            return;
+        };
+        if depth < target {
+            for child in children {
+                self.saturate_level(child, depth + 1, target);
+            }
+            return;
+        }
+        let m = self.index(mid) as usize;
+        let (left, right) = (
+            self.positions[self.index(triangle.left) as usize],
+            self.positions[self.index(triangle.right) as usize],
+        );
+        let mut error = (self.positions[m] - left.midpoint(right)).magnitude();
+        let mut radius = 0.0f32;
+        for (child, _) in children.iter().filter_map(Triangle::split) {
+            let c = self.index(child) as usize;
+            error = error.max(self.errors[c]);
+            radius =
+                radius.max((self.positions[c] - self.positions[m]).magnitude() + self.radii[c]);
+        }
+        self.errors[m] = self.errors[m].max(error);
+        self.radii[m] = self.radii[m].max(radius);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{gpu_test, heightfield::HeightFunction, vertex::Mesh};
+
+    struct Bumps;
+
+    impl HeightFunction for Bumps {
+        fn sample(&self, u: f32, v: f32) -> f32 {
+            (u * 9.0).sin() * (v * 7.0).cos() * 0.1
+        }
+    }
+
+    #[test]
+    fn refined_mesh_has_no_cracks() {
+        let Some((device, _queue)) = gpu_test::fallback_device() else {
+            eprintln!("no fallback adapter, skipping the ROAM test");
+            return;
+        };
+        let n = 32;
+        let mut mesh = Mesh::new(vec![], vec![]);
+        mesh.generate_plane(n, &Bumps);
+        let terrain = Entity::new(
+            mesh,
+            vec3(-1.0, 0.0, 1.0),
+            Quaternion::from_angle_x(Deg(-90.0)),
+            vec3(2.0, 1.0, 2.0),
+        );
+        let roam = RoamTerrain::new(&device, &terrain, RoamSettings::default());
+
+        let near = roam.refine(point3(-0.9, 0.2, 0.9), 500.0);
+        let far = roam.refine(point3(0.0, 20.0, 0.0), 500.0);
+        // Refined near the camera only, so there are edges between levels.
+        assert!(near.len() > far.len());
+        assert!(near.len() < (6 * n * n) as usize);
+
+        let corner = |i: u32| (i / (n + 1), i % (n + 1));
+        let mut edges: HashMap<_, u32> = HashMap::new();
+        let mut area = 0;
+        for t in near.chunks_exact(3) {
+            let [a, b, c] = [t[0], t[1], t[2]]
+                .map(corner)
+                .map(|(u, v)| (u as i64, v as i64));
+            area += (b.0 - a.0) * (c.1 - a.1) - (b.1 - a.1) * (c.0 - a.0);
+            for (p, q) in [(t[0], t[1]), (t[1], t[2]), (t[2], t[0])] {
+                *edges.entry((p.min(q), p.max(q))).or_insert(0) += 1;
+            }
+        }
+        assert_eq!(area, 2 * (n * n) as i64);
+        // Without T-junctions every edge inside the grid has a triangle on
+        // both sides.
+        let on_boundary = |(p, q): (u32, u32)| {
+            let ((pu, pv), (qu, qv)) = (corner(p), corner(q));
+            (pu == qu && (pu == 0 || pu == n)) || (pv == qv && (pv == 0 || pv == n))
+        };
+        for (&edge, &count) in &edges {
+            assert_eq!(count, if on_boundary(edge) { 1 } else { 2 }, "{edge:?}");
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::path::PathBuf;
+
+use anyhow::*;
+use cgmath::Deg;
+
+use crate::{
+    chunk::ChunkSettings,
+    erosion::{HydraulicErosion, ThermalErosion},
+    fractal::TerrainPreset,
+    heightmap::{self, HeightmapImport},
+    light::{self, LampPlacement},
+    model::{self, ModelPlacement},
+    noise::NoiseKind,
+    roam::RoamSettings,
+    shadow::ShadowSettings,
+};
+
+pub struct Settings {
+    pub seed: u64,
+    pub noise_kind: NoiseKind,
+    pub preset: TerrainPreset,
+    pub heightmap: Option<PathBuf>,
+    pub heightmap_import: HeightmapImport,
+    pub erode_heightmap: bool,
+    pub export_dir: PathBuf,
+    pub models: Vec<ModelPlacement>,
+    pub stl_skirt: f32,
+    pub stream: bool,
+    pub chunks: ChunkSettings,
+    pub roam: bool,
+    pub roam_settings: RoamSettings,
+    pub point_lights: usize,
+    pub lamps: Vec<LampPlacement>,
+    pub shadows: ShadowSettings,
+    pub time_of_day: f32,
+    pub day_length: f32,
+    pub droplets: u32,
+    pub thermal_iterations: u32,
+    pub talus_angle: f32,
+    pub software: bool,
+    pub verify_gpu_erosion: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            seed: 1,
+            noise_kind: NoiseKind::Simplex,
+            preset: TerrainPreset::Hills,
+            heightmap: None,
+            heightmap_import: HeightmapImport::default(),
+            erode_heightmap: false,
+            export_dir: PathBuf::from("export"),
+            models: vec![],
+            stl_skirt: 0.05,
+            stream: false,
+            chunks: ChunkSettings::default(),
+            roam: false,
+            roam_settings: RoamSettings::default(),
+            point_lights: 1,
+            lamps: vec![],
+            shadows: ShadowSettings::default(),
+            time_of_day: 10.0,
+            day_length: 120.0,
+            droplets: 100_000,
+            thermal_iterations: 50,
+            talus_angle: 33.0,
+            software: false,
+            verify_gpu_erosion: false,
+        }
+    }
+}
+
+impl Settings {
+    pub fn from_args() -> Result<Self> {
+        let mut settings = Self::default();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .with_context(|| format!("missing value for {arg}"))
+            };
+            match arg.as_str() {
+                "--seed" => settings.seed = value()?.parse()?,
+                "--noise" => settings.noise_kind = value()?.parse()?,
+                "--preset" => settings.preset = value()?.parse()?,
+                "--heightmap" => settings.heightmap = Some(value()?.into()),
+                "--heightmap-size" => {
+                    settings.heightmap_import.size = Some(heightmap::parse_size(&value()?)?)
+                }
+                "--height-scale" => settings.heightmap_import.scale = value()?.parse()?,
+                "--height-offset" => settings.heightmap_import.offset = value()?.parse()?,
+                "--erode-heightmap" => settings.erode_heightmap = true,
+                "--export-dir" => settings.export_dir = value()?.into(),
+                "--stl-skirt" => settings.stl_skirt = value()?.parse()?,
+                "--model" => settings.models.push(model::parse_placement(&value()?)?),
+                "--droplets" => settings.droplets = value()?.parse()?,
+                "--thermal-iterations" => settings.thermal_iterations = value()?.parse()?,
+                "--talus-angle" => settings.talus_angle = value()?.parse()?,
+                "--stream" => settings.stream = true,
+                "--chunk-size" => settings.chunks.size = value()?.parse()?,
+                "--chunk-resolution" => settings.chunks.resolution = value()?.parse()?,
+                "--chunk-radius" => {
+                    settings.chunks.load_radius = value()?.parse()?;
+                    settings.chunks.unload_radius = settings.chunks.load_radius + 2;
+                }
+                "--lod-levels" => settings.chunks.lod.levels = value()?.parse()?,
+                "--lod-distance" => settings.chunks.lod.distance = value()?.parse()?,
+                "--no-occlusion-culling" => settings.chunks.occlusion_culling = false,
+                "--roam" => settings.roam = true,
+                "--roam-error" => settings.roam_settings.tolerance = value()?.parse()?,
+                "--point-lights" => settings.point_lights = value()?.parse()?,
+                "--lamp" => settings.lamps.push(light::parse_lamp(&value()?)?),
+                "--time-of-day" => settings.time_of_day = value()?.parse()?,
+                "--day-length" => settings.day_length = value()?.parse()?,
+                "--shadow-resolution" => settings.shadows.resolution = value()?.parse()?,
+                "--shadow-distance" => settings.shadows.distance = value()?.parse()?,
+                "--point-shadow-resolution" => {
+                    settings.shadows.point_resolution = value()?.parse()?
+                }
+                "--software" => settings.software = true,
+                "--verify-gpu-erosion" => settings.verify_gpu_erosion = true,
+                _ => bail!("unknown argument {arg}"),
+            }
+        }
+        ensure!(
+            settings.chunks.size > 0.0,
+            "--chunk-size must be greater than 0"
+        );
+        ensure!(
+            settings.chunks.resolution > 0,
+            "--chunk-resolution must be greater than 0"
+        );
+        ensure!(
+            !(settings.stream && settings.heightmap.is_some()),
+            "--stream generates terrain from noise and can't be used with --heightmap"
+        );
+        ensure!(
+            !(settings.stream && settings.roam),
+            "--roam triangulates the single plane and can't be used with --stream"
+        );
+        ensure!(
+            settings.point_lights > 0,
+            "--point-lights needs at least one light"
+        );
+        ensure!(
+            settings.shadows.resolution > 0,
+            "--shadow-resolution must be greater than 0"
+        );
+        ensure!(
+            settings.shadows.point_resolution > 0,
+            "--point-shadow-resolution must be greater than 0"
+        );
+        Ok(settings)
+    }
+
+    pub fn hydraulic_erosion(&self) -> HydraulicErosion {
+        HydraulicErosion {
+            droplets: self.droplets,
+            ..Default::default()
+        }
+    }
+
+    pub fn thermal_erosion(&self) -> ThermalErosion {
+        ThermalErosion {
+            iterations: self.thermal_iterations,
+            talus_angle: Deg(self.talus_angle),
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,78 @@
+// GPU version of ThermalErosion in src/erosion.rs. One iteration is
+// cs_outflow followed by cs_apply; the host then copies `next` back into
+// `heights`. Keep the arithmetic in the same order as the CPU code so both
+// produce the same terrain.
+
+struct ThermalParams {
+    talus: vec2<f32>,
+    strength: f32,
+    _padding: f32,
+};
+
+@group(0) @binding(0)
+var<uniform> params: ThermalParams;
+@group(0) @binding(1)
+var heights: texture_2d<f32>;
+
+// cs_outflow binds only `outflow_out` in group 1, cs_apply only `outflow_in` and `next`.
+@group(1) @binding(0)
+var outflow_out: texture_storage_2d<rgba32float, write>;
+@group(1) @binding(1)
+var outflow_in: texture_2d<f32>;
+@group(1) @binding(2)
+var next: texture_storage_2d<r32float, write>;
+
+fn excess(h: f32, coord: vec2<i32>, dims: vec2<i32>, talus: f32) -> f32 {
+    if any(coord < vec2<i32>(0)) || any(coord >= dims) {
+        return 0.0;
+    }
+    return max(h - textureLoad(heights, coord, 0).r - talus, 0.0);
+}
+
+@compute @workgroup_size(8, 8)
+fn cs_outflow(@builtin(global_invocation_id) id: vec3<u32>) {
+    let dims = vec2<i32>(textureDimensions(heights));
+    let c = vec2<i32>(id.xy);
+    if any(c >= dims) {
+        return;
+    }
+    let h = textureLoad(heights, c, 0).r;
+    let e = vec4<f32>(
+        excess(h, c + vec2<i32>(1, 0), dims, params.talus.x),
+        excess(h, c - vec2<i32>(1, 0), dims, params.talus.x),
+        excess(h, c + vec2<i32>(0, 1), dims, params.talus.y),
+        excess(h, c - vec2<i32>(0, 1), dims, params.talus.y),
+    );
+    let total = e.x + e.y + e.z + e.w;
+    var out = vec4<f32>(0.0);
+    if total > 0.0 {
+        let moved = max(max(max(e.x, e.y), e.z), e.w) * 0.5 * params.strength;
+        out = moved * e / total;
+    }
+    textureStore(outflow_out, c, out);
+}
+
+@compute @workgroup_size(8, 8)
+fn cs_apply(@builtin(global_invocation_id) id: vec3<u32>) {
+    let dims = vec2<i32>(textureDimensions(heights));
+    let c = vec2<i32>(id.xy);
+    if any(c >= dims) {
+        return;
+    }
+    let out = textureLoad(outflow_in, c, 0);
+    var inflow = 0.0;
+    if c.x > 0 {
+        inflow += textureLoad(outflow_in, c - vec2<i32>(1, 0), 0).x;
+    }
+    if c.x + 1 < dims.x {
+        inflow += textureLoad(outflow_in, c + vec2<i32>(1, 0), 0).y;
+    }
+    if c.y > 0 {
+        inflow += textureLoad(outflow_in, c - vec2<i32>(0, 1), 0).z;
+    }
+    if c.y + 1 < dims.y {
+        inflow += textureLoad(outflow_in, c + vec2<i32>(0, 1), 0).w;
+    }
+    let h = textureLoad(heights, c, 0).r;
+    textureStore(next, c, vec4<f32>(h - (out.x + out.y + out.z + out.w) + inflow, 0.0, 0.0, 0.0));
+}
@@ -0,0 +1,215 @@
+// Mirror of src/noise.rs. The including shader declares the seeded permutation
+// table as `noise_perm: array<vec4<u32>, 64>` (256 entries, four per element).
+// The noise tests run this on the software adapter and check it against the
+// CPU version.
+
+fn noise_perm_at(i: i32) -> i32 {
+    let j = i & 255;
+    return i32(noise_perm[j / 4][j % 4]);
+}
+
+fn noise_hash2(x: i32, y: i32) -> i32 {
+    return noise_perm_at(noise_perm_at(x & 255) + (y & 255));
+}
+
+fn noise_hash3(x: i32, y: i32, z: i32) -> i32 {
+    return noise_perm_at(noise_hash2(x, y) + (z & 255));
+}
+
+fn noise_fade(t: f32) -> f32 {
+    return t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+}
+
+fn noise_value(h: i32) -> f32 {
+    return f32(h) / 127.5 - 1.0;
+}
+
+fn noise_grad2(h: i32, x: f32, y: f32) -> f32 {
+    switch h & 7 {
+        case 0: { return x + y; }
+        case 1: { return -x + y; }
+        case 2: { return x - y; }
+        case 3: { return -x - y; }
+        case 4: { return x; }
+        case 5: { return -x; }
+        case 6: { return y; }
+        default: { return -y; }
+    }
+}
+
+fn noise_grad3(h: i32, x: f32, y: f32, z: f32) -> f32 {
+    switch h & 15 {
+        case 0, 12: { return x + y; }
+        case 1, 14: { return -x + y; }
+        case 2: { return x - y; }
+        case 3: { return -x - y; }
+        case 4: { return x + z; }
+        case 5: { return -x + z; }
+        case 6: { return x - z; }
+        case 7: { return -x - z; }
+        case 8: { return y + z; }
+        case 9, 13: { return -y + z; }
+        case 10: { return y - z; }
+        default: { return -y - z; }
+    }
+}
+
+fn noise_perlin2(p: vec2<f32>) -> f32 {
+    let p0 = floor(p);
+    let f = p - p0;
+    let i = vec2<i32>(p0);
+    let u = noise_fade(f.x);
+    let v = noise_fade(f.y);
+
+    let n00 = noise_grad2(noise_hash2(i.x, i.y), f.x, f.y);
+    let n10 = noise_grad2(noise_hash2(i.x + 1, i.y), f.x - 1.0, f.y);
+    let n01 = noise_grad2(noise_hash2(i.x, i.y + 1), f.x, f.y - 1.0);
+    let n11 = noise_grad2(noise_hash2(i.x + 1, i.y + 1), f.x - 1.0, f.y - 1.0);
+    return mix(mix(n00, n10, u), mix(n01, n11, u), v);
+}
+
+fn noise_perlin3(p: vec3<f32>) -> f32 {
+    let p0 = floor(p);
+    let f = p - p0;
+    let i = vec3<i32>(p0);
+    let u = noise_fade(f.x);
+    let v = noise_fade(f.y);
+    let w = noise_fade(f.z);
+
+    let n000 = noise_grad3(noise_hash3(i.x, i.y, i.z), f.x, f.y, f.z);
+    let n100 = noise_grad3(noise_hash3(i.x + 1, i.y, i.z), f.x - 1.0, f.y, f.z);
+    let n010 = noise_grad3(noise_hash3(i.x, i.y + 1, i.z), f.x, f.y - 1.0, f.z);
+    let n110 = noise_grad3(noise_hash3(i.x + 1, i.y + 1, i.z), f.x - 1.0, f.y - 1.0, f.z);
+    let n001 = noise_grad3(noise_hash3(i.x, i.y, i.z + 1), f.x, f.y, f.z - 1.0);
+    let n101 = noise_grad3(noise_hash3(i.x + 1, i.y, i.z + 1), f.x - 1.0, f.y, f.z - 1.0);
+    let n011 = noise_grad3(noise_hash3(i.x, i.y + 1, i.z + 1), f.x, f.y - 1.0, f.z - 1.0);
+    let n111 = noise_grad3(noise_hash3(i.x + 1, i.y + 1, i.z + 1), f.x - 1.0, f.y - 1.0, f.z - 1.0);
+    let nx00 = mix(n000, n100, u);
+    let nx10 = mix(n010, n110, u);
+    let nx01 = mix(n001, n101, u);
+    let nx11 = mix(n011, n111, u);
+    return mix(mix(nx00, nx10, v), mix(nx01, nx11, v), w);
+}
+
+fn noise_simplex2_corner(h: i32, x: f32, y: f32) -> f32 {
+    let t = 0.5 - x * x - y * y;
+    if t < 0.0 {
+        return 0.0;
+    }
+    return t * t * t * t * noise_grad2(h, x, y);
+}
+
+fn noise_simplex2(p: vec2<f32>) -> f32 {
+    let F2 = 0.3660254;
+    let G2 = 0.21132487;
+    let s = (p.x + p.y) * F2;
+    let ij = floor(p + s);
+    let t = (ij.x + ij.y) * G2;
+    let p0 = p - (ij - t);
+    var o = vec2<i32>(0, 1);
+    if p0.x > p0.y {
+        o = vec2<i32>(1, 0);
+    }
+    let p1 = p0 - vec2<f32>(o) + G2;
+    let p2 = p0 - 1.0 + 2.0 * G2;
+    let i = vec2<i32>(ij);
+
+    let n0 = noise_simplex2_corner(noise_hash2(i.x, i.y), p0.x, p0.y);
+    let n1 = noise_simplex2_corner(noise_hash2(i.x + o.x, i.y + o.y), p1.x, p1.y);
+    let n2 = noise_simplex2_corner(noise_hash2(i.x + 1, i.y + 1), p2.x, p2.y);
+    return 70.0 * (n0 + n1 + n2);
+}
+
+fn noise_simplex3_corner(h: i32, p: vec3<f32>) -> f32 {
+    let t = 0.6 - dot(p, p);
+    if t < 0.0 {
+        return 0.0;
+    }
+    return t * t * t * t * noise_grad3(h, p.x, p.y, p.z);
+}
+
+fn noise_simplex3(p: vec3<f32>) -> f32 {
+    let F3 = 1.0 / 3.0;
+    let G3 = 1.0 / 6.0;
+    let s = (p.x + p.y + p.z) * F3;
+    let ijk = floor(p + s);
+    let t = (ijk.x + ijk.y + ijk.z) * G3;
+    let p0 = p - (ijk - t);
+    var o1: vec3<i32>;
+    var o2: vec3<i32>;
+    if p0.x >= p0.y {
+        if p0.y >= p0.z {
+            o1 = vec3<i32>(1, 0, 0);
+            o2 = vec3<i32>(1, 1, 0);
+        } else if p0.x >= p0.z {
+            o1 = vec3<i32>(1, 0, 0);
+            o2 = vec3<i32>(1, 0, 1);
+        } else {
+            o1 = vec3<i32>(0, 0, 1);
+            o2 = vec3<i32>(1, 0, 1);
+        }
+    } else if p0.y < p0.z {
+        o1 = vec3<i32>(0, 0, 1);
+        o2 = vec3<i32>(0, 1, 1);
+    } else if p0.x < p0.z {
+        o1 = vec3<i32>(0, 1, 0);
+        o2 = vec3<i32>(0, 1, 1);
+    } else {
+        o1 = vec3<i32>(0, 1, 0);
+        o2 = vec3<i32>(1, 1, 0);
+    }
+    let p1 = p0 - vec3<f32>(o1) + G3;
+    let p2 = p0 - vec3<f32>(o2) + 2.0 * G3;
+    let p3 = p0 - 1.0 + 3.0 * G3;
+    let i = vec3<i32>(ijk);
+
+    let n0 = noise_simplex3_corner(noise_hash3(i.x, i.y, i.z), p0);
+    let n1 = noise_simplex3_corner(noise_hash3(i.x + o1.x, i.y + o1.y, i.z + o1.z), p1);
+    let n2 = noise_simplex3_corner(noise_hash3(i.x + o2.x, i.y + o2.y, i.z + o2.z), p2);
+    let n3 = noise_simplex3_corner(noise_hash3(i.x + 1, i.y + 1, i.z + 1), p3);
+    return 32.0 * (n0 + n1 + n2 + n3);
+}
+
+fn noise_value2(p: vec2<f32>) -> f32 {
+    let p0 = floor(p);
+    let i = vec2<i32>(p0);
+    let u = noise_fade(p.x - p0.x);
+    let v = noise_fade(p.y - p0.y);
+
+    let n00 = noise_value(noise_hash2(i.x, i.y));
+    let n10 = noise_value(noise_hash2(i.x + 1, i.y));
+    let n01 = noise_value(noise_hash2(i.x, i.y + 1));
+    let n11 = noise_value(noise_hash2(i.x + 1, i.y + 1));
+    return mix(mix(n00, n10, u), mix(n01, n11, u), v);
+}
+
+fn noise_value3(p: vec3<f32>) -> f32 {
+    let p0 = floor(p);
+    let i = vec3<i32>(p0);
+    let u = noise_fade(p.x - p0.x);
+    let v = noise_fade(p.y - p0.y);
+    let w = noise_fade(p.z - p0.z);
+
+    let nx00 = mix(noise_value(noise_hash3(i.x, i.y, i.z)), noise_value(noise_hash3(i.x + 1, i.y, i.z)), u);
+    let nx10 = mix(noise_value(noise_hash3(i.x, i.y + 1, i.z)), noise_value(noise_hash3(i.x + 1, i.y + 1, i.z)), u);
+    let nx01 = mix(noise_value(noise_hash3(i.x, i.y, i.z + 1)), noise_value(noise_hash3(i.x + 1, i.y, i.z + 1)), u);
+    let nx11 = mix(noise_value(noise_hash3(i.x, i.y + 1, i.z + 1)), noise_value(noise_hash3(i.x + 1, i.y + 1, i.z + 1)), u);
+    return mix(mix(nx00, nx10, v), mix(nx01, nx11, v), w);
+}
+
+// kind: 0 = Perlin, 1 = Simplex, 2 = Value, matching NoiseKind.
+fn noise_sample2(kind: u32, p: vec2<f32>) -> f32 {
+    switch kind {
+        case 0u: { return noise_perlin2(p); }
+        case 1u: { return noise_simplex2(p); }
+        default: { return noise_value2(p); }
+    }
+}
+
+fn noise_sample3(kind: u32, p: vec3<f32>) -> f32 {
+    switch kind {
+        case 0u: { return noise_perlin3(p); }
+        case 1u: { return noise_simplex3(p); }
+        default: { return noise_value3(p); }
+    }
+}
@@ -26,12 +26,66 @@ struct Camera {
 @group(0) @binding(0)
 var<uniform> camera: Camera;
 
+// position.w is the light's radius and color.a its intensity.
 struct PointLight {
     position: vec4<f32>,
-    diffuse_color: vec4<f32>,
+    color: vec4<f32>,
+};
+
+struct PointLights {
+    count: u32,
+    lights: array<PointLight>,
 };
 @group(1) @binding(0)
-var<uniform> p_light: PointLight;
+var<storage, read> point_lights: PointLights;
+
+// direction is the way the light travels and color.a its intensity.
+struct DirectionalLight {
+    direction: vec4<f32>,
+    color: vec4<f32>,
+};
+@group(1) @binding(1)
+var<uniform> sun: DirectionalLight;
+
+// As PointLight, plus the cosines of the inner and outer cone angles in cone.
+struct SpotLight {
+    position: vec4<f32>,
+    direction: vec4<f32>,
+    color: vec4<f32>,
+    cone: vec4<f32>,
+};
+
+struct SpotLights {
+    count: u32,
+    lights: array<SpotLight>,
+};
+@group(1) @binding(2)
+var<storage, read> spot_lights: SpotLights;
+
+// The sun's cascades: each one's view projection, the view depth it ends at
+// and the world size of one of its texels.
+struct Shadows {
+    view_projections: array<mat4x4<f32>, 4>,
+    splits: vec4<f32>,
+    texel_sizes: vec4<f32>,
+};
+@group(1) @binding(3)
+var shadow_map: texture_depth_2d_array;
+@group(1) @binding(4)
+var shadow_sampler: sampler_comparison;
+@group(1) @binding(5)
+var<uniform> shadows: Shadows;
+
+// Six faces for each of the first few point lights, in the order +x, -x, +y,
+// -y, +z, -z, and how many lights have them.
+struct PointShadows {
+    view_projections: array<mat4x4<f32>, 24>,
+    count: u32,
+};
+@group(1) @binding(6)
+var point_shadow_map: texture_depth_2d_array;
+@group(1) @binding(7)
+var<uniform> point_shadows: PointShadows;
 
 @group(2) @binding(0)
 var t_diffuse: texture_2d<f32>;
@@ -42,6 +96,9 @@ var t_normal: texture_2d<f32>;
 @group(2) @binding(3)
 var s_normal: sampler;
 
+@group(3) @binding(0)
+var height_map: texture_2d<f32>;
+
 struct EntityData {
     @location(4) model_0: vec4<f32>,
     @location(5) model_1: vec4<f32>,
@@ -59,6 +116,10 @@ fn vs_main(
     in: VertexInput,
     data: EntityData,
 ) -> VertexOutput {
+    return transform_vertex(in, data);
+}
+
+fn transform_vertex(in: VertexInput, data: EntityData) -> VertexOutput {
     let model = mat4x4<f32>(
         data.model_0,
         data.model_1,
@@ -78,30 +139,78 @@ fn vs_main(
     out.norm_mat_1 = data.normal_mat_1;
     out.norm_mat_2 = data.normal_mat_2;
     out.norm_mat_3 = data.normal_mat_3;
-    let u = in.position.x;
-    let v = in.position.y;
-    let new_pos = vec4<f32>(plane_func(u, v), 1.0);
-    let neigh1 = plane_func(u + 0.001, v);
-    let neigh2 = plane_func(u, v + 0.001);
-    let tangent = normalize(neigh1 - new_pos.xyz);
-    let bitangent = normalize(neigh2 - new_pos.xyz);
-    let norm = normalize(cross(tangent, bitangent));
-
-    let world_position = model * new_pos;
+    let world_position = model * in.position;
     out.world_position = world_position.xyz;
 
-    out.frag_position = camera.projection * camera.view * model * new_pos;
-    // out.world_normal = (plane_entity_data.normal_mat * in.normal).xyz;
-    out.world_normal = (norm_mat * vec4<f32>(norm, 1.0)).xyz;
+    out.frag_position = camera.projection * camera.view * model * in.position;
+    out.world_normal = (norm_mat * in.normal).xyz;
     return out;
 }
 
+fn terrain_height(coord: vec2<i32>) -> f32 {
+    let dims = vec2<i32>(textureDimensions(height_map));
+    return textureLoad(height_map, clamp(coord, vec2<i32>(0), dims - 1), 0).r;
+}
+
+// Bilinear height between texels, at a uv on the terrain.
+fn terrain_height_uv(uv: vec2<f32>) -> f32 {
+    let texel = uv * vec2<f32>(textureDimensions(height_map) - 1u);
+    let base = floor(texel);
+    let f = texel - base;
+    let coord = vec2<i32>(base);
+    let h0 = mix(terrain_height(coord), terrain_height(coord + vec2<i32>(1, 0)), f.x);
+    let h1 = mix(terrain_height(coord + vec2<i32>(0, 1)), terrain_height(coord + vec2<i32>(1, 1)), f.x);
+    return mix(h0, h1, f.y);
+}
 
-fn plane_func(u: f32, v: f32) -> vec3<f32> {
-    let z = sin(u * 10.0 * 3.1415) * 0.01 + sin(v * 5.0 * 3.1415) * 0.05;
-    return vec3<f32>(u, v, z);
+// Object space normal from the texels either side of the one nearest uv.
+fn terrain_normal(uv: vec2<f32>) -> vec3<f32> {
+    let dims = vec2<f32>(textureDimensions(height_map) - 1u);
+    let coord = vec2<i32>(round(uv * dims));
+    let dh_du = (terrain_height(coord + vec2<i32>(1, 0)) - terrain_height(coord - vec2<i32>(1, 0))) * dims.x * 0.5;
+    let dh_dv = (terrain_height(coord + vec2<i32>(0, 1)) - terrain_height(coord - vec2<i32>(0, 1))) * dims.y * 0.5;
+    return normalize(vec3<f32>(-dh_du, -dh_dv, 1.0));
 }
 
+struct PatchData {
+    @location(12) placement: vec4<f32>,
+    @location(13) morph: vec4<f32>,
+};
+
+// One clipmap patch: the grid mesh spans 0..1 in tex_coords and is placed at
+// placement.xy in terrain uv with size placement.z and placement.w quads a side. Heights
+// come from the height texture, so GPU erosion shows up without an upload.
+@vertex
+fn vs_clipmap(
+    in: VertexInput,
+    data: EntityData,
+    patch_data: PatchData,
+) -> VertexOutput {
+    let model = mat4x4<f32>(
+        data.model_0,
+        data.model_1,
+        data.model_2,
+        data.model_3,
+    );
+    let uv = patch_data.placement.xy + in.tex_coords * patch_data.placement.z;
+    let world_position = model * vec4<f32>(uv, terrain_height_uv(uv), 1.0);
+    let k = clamp(
+        (distance(world_position.xyz, camera.position.xyz) - patch_data.morph.x) / (patch_data.morph.y - patch_data.morph.x),
+        0.0,
+        1.0,
+    );
+    // Odd grid lines slide onto their even neighbours, so a fully morphed
+    // patch has the vertices of the next coarser level.
+    let cell = round(in.tex_coords * patch_data.placement.w);
+    let morphed = (cell - fract(cell * 0.5) * 2.0 * k) / patch_data.placement.w;
+    let morphed_uv = patch_data.placement.xy + morphed * patch_data.placement.z;
+
+    var vertex = in;
+    vertex.position = vec4<f32>(morphed_uv, terrain_height_uv(morphed_uv), 1.0);
+    vertex.normal = vec4<f32>(terrain_normal(morphed_uv), 1.0);
+    vertex.tex_coords = morphed_uv;
+    return transform_vertex(vertex, data);
+}
 
 // Fragment shader
 @fragment
@@ -116,25 +225,141 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     );
     var norm = normalize((norm_mat * obj_norm * 2.0 - 1.0).xyz);
 
+    return phong(in, col, norm);
+}
 
-    let ambient_strength = 0.1;
-    let ambient_col = p_light.diffuse_color.rgb * ambient_strength;
+// The terrain's normal map is in object space, which only works for the plane,
+// so imported models light with their interpolated vertex normals instead.
+@fragment
+fn fs_model(in: VertexOutput) -> @location(0) vec4<f32> {
+    let col: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.tex_coords) * in.color;
+    return phong(in, col, normalize(in.world_normal));
+}
 
-    let light_vec = (p_light.position).xyz - in.world_position;
-    let light_dir = normalize(light_vec.xyz);
+fn phong(in: VertexOutput, col: vec4<f32>, norm: vec3<f32>) -> vec4<f32> {
     let view_dir = normalize(camera.position.xyz - in.world_position);
-    let reflect_dir = reflect(-light_dir, norm.xyz);
+    var light = directional_light(sun, norm, view_dir, sun_shadow(in.world_position, norm));
+    let count = min(point_lights.count, arrayLength(&point_lights.lights));
+    for (var i = 0u; i < count; i++) {
+        let point = point_lights.lights[i];
+        let shadow = point_shadow(i, point.position.xyz, in.world_position, norm);
+        light += point_light(point, in.world_position, norm, view_dir, shadow);
+    }
+    let spot_count = min(spot_lights.count, arrayLength(&spot_lights.lights));
+    for (var i = 0u; i < spot_count; i++) {
+        light += spot_light(spot_lights.lights[i], in.world_position, norm, view_dir);
+    }
+    return vec4<f32>(light * col.rgb, 1.0);
+}
 
-    let dist = length(light_vec.xyz);
-    let atten = 1.0 / (max(0.01, dist) * max(0.01, dist));
-    let diffuse_strength = max(dot(norm.xyz, light_dir), 0.0) * atten;
-    let diff_color = p_light.diffuse_color.rgb * diffuse_strength;
+fn point_light(light: PointLight, world_position: vec3<f32>, norm: vec3<f32>, view_dir: vec3<f32>, shadow: f32) -> vec3<f32> {
+    let color = light.color.rgb * light.color.a;
 
-    let specular_strength = pow(max(dot(view_dir, reflect_dir), 0.0), 32) * 0.5;
-    let specular_color = p_light.diffuse_color.rgb * specular_strength;
+    let light_vec = light.position.xyz - world_position;
+    let light_dir = normalize(light_vec);
+    let reflect_dir = reflect(-light_dir, norm);
 
-    let result = vec4<f32>((ambient_col + diff_color + specular_color) * col.rgb, 1.0);
+    // Inverse square, windowed so it reaches zero at the light's radius. All
+    // three terms fade with it, so lights add nothing outside their reach.
+    let dist = length(light_vec);
+    let window = saturate(1.0 - pow(dist / light.position.w, 4.0));
+    let atten = window * window / (max(0.01, dist) * max(0.01, dist));
+
+    let ambient_strength = 0.1;
+    let diffuse_strength = max(dot(norm, light_dir), 0.0) * shadow;
+    let specular_strength = pow(max(dot(view_dir, reflect_dir), 0.0), 32) * 0.5 * shadow;
+    return color * (ambient_strength + diffuse_strength + specular_strength) * atten;
+}
+
+// How much of the sun reaches a point, from 0 in full shadow to 1. Past the
+// last cascade everything is lit.
+fn sun_shadow(world_position: vec3<f32>, norm: vec3<f32>) -> f32 {
+    let depth = -(camera.view * vec4<f32>(world_position, 1.0)).z;
+    var cascade = 0u;
+    while (cascade < 4u && depth > shadows.splits[cascade]) {
+        cascade++;
+    }
+    if (cascade == 4u) {
+        return 1.0;
+    }
+    // Pushed out along the normal, against acne on slopes facing away from
+    // the sun's texels.
+    let offset = world_position + norm * shadows.texel_sizes[cascade] * 1.5;
+    let clip = shadows.view_projections[cascade] * vec4<f32>(offset, 1.0);
+    return filtered_shadow(shadow_map, clip.xyz / clip.w, cascade);
+}
 
-    return result;
+// The same for point light `i`, from the face of its cube the point lies in.
+// Lights without a shadow light everything.
+fn point_shadow(i: u32, light_position: vec3<f32>, world_position: vec3<f32>, norm: vec3<f32>) -> f32 {
+    if (i >= point_shadows.count) {
+        return 1.0;
+    }
+    // A texel of a 90 degree face is 2 / resolution wide at unit distance.
+    let resolution = f32(textureDimensions(point_shadow_map).x);
+    let texel_size = length(world_position - light_position) * 2.0 / resolution;
+    let offset = world_position + norm * texel_size * 1.5;
+    let to_point = offset - light_position;
+    let axis = abs(to_point);
+    var face = 0u;
+    if (axis.x >= axis.y && axis.x >= axis.z) {
+        face = select(1u, 0u, to_point.x > 0.0);
+    } else if (axis.y >= axis.z) {
+        face = select(3u, 2u, to_point.y > 0.0);
+    } else {
+        face = select(5u, 4u, to_point.z > 0.0);
+    }
+    let layer = i * 6u + face;
+    let clip = point_shadows.view_projections[layer] * vec4<f32>(offset, 1.0);
+    return filtered_shadow(point_shadow_map, clip.xyz / clip.w, layer);
 }
 
+// 3x3 percentage closer filtering of `layer` around ndc, on top of the
+// sampler's own 2x2.
+fn filtered_shadow(map: texture_depth_2d_array, ndc: vec3<f32>, layer: u32) -> f32 {
+    let uv = ndc.xy * vec2<f32>(0.5, -0.5) + 0.5;
+    let texel = 1.0 / f32(textureDimensions(map).x);
+    var lit = 0.0;
+    for (var y = -1; y <= 1; y++) {
+        for (var x = -1; x <= 1; x++) {
+            let at = uv + vec2<f32>(f32(x), f32(y)) * texel;
+            lit += textureSampleCompareLevel(map, shadow_sampler, at, layer, ndc.z);
+        }
+    }
+    return lit / 9.0;
+}
+
+fn directional_light(light: DirectionalLight, norm: vec3<f32>, view_dir: vec3<f32>, shadow: f32) -> vec3<f32> {
+    let color = light.color.rgb * light.color.a;
+    let ambient_strength = 0.1;
+    let ambient_col = color * ambient_strength;
+
+    let light_dir = -light.direction.xyz;
+    let reflect_dir = reflect(-light_dir, norm);
+
+    let diffuse_strength = max(dot(norm, light_dir), 0.0) * shadow;
+    let diff_color = color * diffuse_strength;
+
+    let specular_strength = pow(max(dot(view_dir, reflect_dir), 0.0), 32) * 0.5 * shadow;
+    let specular_color = color * specular_strength;
+
+    return ambient_col + diff_color + specular_color;
+}
+
+// No ambient term: a spot light shouldn't brighten what's outside its cone.
+fn spot_light(light: SpotLight, world_position: vec3<f32>, norm: vec3<f32>, view_dir: vec3<f32>) -> vec3<f32> {
+    let color = light.color.rgb * light.color.a;
+
+    let light_vec = light.position.xyz - world_position;
+    let light_dir = normalize(light_vec);
+    let reflect_dir = reflect(-light_dir, norm);
+
+    let dist = length(light_vec);
+    let window = saturate(1.0 - pow(dist / light.position.w, 4.0));
+    let cone = smoothstep(light.cone.y, light.cone.x, dot(-light_dir, light.direction.xyz));
+    let atten = cone * window * window / (max(0.01, dist) * max(0.01, dist));
+
+    let diffuse_strength = max(dot(norm, light_dir), 0.0);
+    let specular_strength = pow(max(dot(view_dir, reflect_dir), 0.0), 32) * 0.5;
+    return color * (diffuse_strength + specular_strength) * atten;
+}
@@ -0,0 +1,470 @@
+use bytemuck::{Pod, Zeroable};
+use cgmath::*;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    camera::{CameraUniformData, CameraWrapper, OPENGL_TO_WGPU_MATRIX},
+    frustum::Frustum,
+    light::PointLight,
+    texture,
+};
+
+pub const CASCADES: usize = 4;
+
+// How far towards the sun, past a cascade's bounds, shadow casters are kept.
+const CASTER_MARGIN: f32 = 20.0;
+
+#[derive(Debug, Clone)]
+pub struct ShadowSettings {
+    // Texels along each side of every cascade.
+    pub resolution: u32,
+    // Distance from the camera, in world units, that shadows reach.
+    pub distance: f32,
+    // How far the splits lean from evenly spaced (0) towards logarithmic (1),
+    // which gives near cascades more of the resolution.
+    pub split_lambda: f32,
+    // Texels along each side of every cube face of a point light's shadow.
+    pub point_resolution: u32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            resolution: 2048,
+            distance: 30.0,
+            split_lambda: 0.75,
+            point_resolution: 512,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct ShadowUniformData {
+    view_projections: [[[f32; 4]; 4]; CASCADES],
+    // The view space depth at which each cascade ends.
+    splits: [f32; CASCADES],
+    // World size of a texel in each cascade.
+    texel_sizes: [f32; CASCADES],
+}
+
+// One layer of a shadow map and the light's camera for drawing into it.
+pub struct ShadowLayer {
+    pub view: wgpu::TextureView,
+    pub bind_group: wgpu::BindGroup,
+    pub frustum: Frustum,
+    camera_buf: wgpu::Buffer,
+}
+
+impl ShadowLayer {
+    fn new(
+        device: &wgpu::Device,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        texture: &wgpu::Texture,
+        layer: u32,
+    ) -> Self {
+        let camera_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("shadow camera"),
+            contents: bytemuck::bytes_of(&CameraUniformData {
+                view: Matrix4::identity().into(),
+                proj: Matrix4::identity().into(),
+                pos: [0.0; 4],
+            }),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("shadow camera bind group"),
+            layout: camera_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: camera_buf.as_entire_binding(),
+            }],
+        });
+        Self {
+            view: texture.create_view(&wgpu::TextureViewDescriptor {
+                dimension: Some(wgpu::TextureViewDimension::D2),
+                base_array_layer: layer,
+                array_layer_count: Some(1),
+                ..Default::default()
+            }),
+            bind_group,
+            frustum: Frustum::new(Matrix4::identity()),
+            camera_buf,
+        }
+    }
+
+    // The clipmap morphs by distance from the real camera, so the shadows
+    // have to as well.
+    fn write(
+        &mut self,
+        queue: &wgpu::Queue,
+        view: Matrix4<f32>,
+        proj: Matrix4<f32>,
+        camera_position: Vector3<f32>,
+    ) {
+        let camera_data = CameraUniformData {
+            view: view.into(),
+            proj: proj.into(),
+            pos: Point3::from_vec(camera_position).to_homogeneous().into(),
+        };
+        queue.write_buffer(&self.camera_buf, 0, bytemuck::bytes_of(&camera_data));
+        self.frustum = Frustum::new(proj * view);
+    }
+}
+
+// Shadows from the sun, drawn into a depth texture array. The camera's view
+// is split by depth and each piece gets its own layer, fitted around it from
+// the sun's side, so nearby shadows get as many texels as distant ones. The
+// fit is a sphere snapped to whole texels, which keeps the shadow edges from
+// crawling as the camera turns and moves.
+pub struct ShadowMap {
+    settings: ShadowSettings,
+    view: wgpu::TextureView,
+    sampler: wgpu::Sampler,
+    uniform_buf: wgpu::Buffer,
+    cascades: Vec<ShadowLayer>,
+    casters: Frustum,
+}
+
+impl ShadowMap {
+    pub fn new(
+        device: &wgpu::Device,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        settings: ShadowSettings,
+    ) -> Self {
+        let texture = texture::create_depth_texture(
+            device,
+            "shadow map",
+            settings.resolution,
+            settings.resolution,
+            CASCADES as u32,
+        );
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("shadow sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+        let uniform_buf = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("shadow uniform"),
+            size: size_of::<ShadowUniformData>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let cascades = (0..CASCADES as u32)
+            .map(|layer| ShadowLayer::new(device, camera_bind_group_layout, &texture, layer))
+            .collect();
+        Self {
+            settings,
+            view,
+            sampler,
+            uniform_buf,
+            cascades,
+            casters: Frustum::new(Matrix4::identity()),
+        }
+    }
+
+    // All cascades as one array, for sampling.
+    pub fn view(&self) -> &wgpu::TextureView {
+        &self.view
+    }
+
+    pub fn sampler(&self) -> &wgpu::Sampler {
+        &self.sampler
+    }
+
+    pub fn uniform_buf(&self) -> &wgpu::Buffer {
+        &self.uniform_buf
+    }
+
+    pub fn cascades(&self) -> &[ShadowLayer] {
+        &self.cascades
+    }
+
+    // Covers every cascade, for picking what to draw into them up front.
+    pub fn casters(&self) -> &Frustum {
+        &self.casters
+    }
+
+    // Fits the cascades to the camera for a sun shining along `direction`.
+    pub fn update(
+        &mut self,
+        queue: &wgpu::Queue,
+        camera: &CameraWrapper,
+        aspect: f32,
+        znear: f32,
+        direction: Vector3<f32>,
+    ) {
+        let (near, far) = (znear, self.settings.distance);
+        let splits = self.settings.splits(near);
+
+        let mut uniform = ShadowUniformData {
+            view_projections: [[[0.0; 4]; 4]; CASCADES],
+            splits,
+            texel_sizes: [0.0; CASCADES],
+        };
+        let mut start = near;
+        for (i, cascade) in self.cascades.iter_mut().enumerate() {
+            let (view, proj, texel_size) = self
+                .settings
+                .fit(camera, aspect, start, splits[i], direction);
+            cascade.write(queue, view, proj, camera.position());
+            uniform.view_projections[i] = (proj * view).into();
+            uniform.texel_sizes[i] = texel_size;
+            start = splits[i];
+        }
+        let (view, proj, _) = self.settings.fit(camera, aspect, near, far, direction);
+        self.casters = Frustum::new(proj * view);
+        queue.write_buffer(&self.uniform_buf, 0, bytemuck::bytes_of(&uniform));
+    }
+}
+
+// Point lights past this many don't cast shadows.
+pub const SHADOWED_POINT_LIGHTS: usize = 4;
+
+const CUBE_FACES: [(Vector3<f32>, Vector3<f32>); 6] = [
+    (Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, -1.0, 0.0)),
+    (Vector3::new(-1.0, 0.0, 0.0), Vector3::new(0.0, -1.0, 0.0)),
+    (Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, 0.0, 1.0)),
+    (Vector3::new(0.0, -1.0, 0.0), Vector3::new(0.0, 0.0, -1.0)),
+    (Vector3::new(0.0, 0.0, 1.0), Vector3::new(0.0, -1.0, 0.0)),
+    (Vector3::new(0.0, 0.0, -1.0), Vector3::new(0.0, -1.0, 0.0)),
+];
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct PointShadowUniformData {
+    // Six faces per light, in the order +x, -x, +y, -y, +z, -z.
+    view_projections: [[[f32; 4]; 4]; 6 * SHADOWED_POINT_LIGHTS],
+    count: u32,
+    _padding: [u32; 3],
+}
+
+// Shadows from the first few point lights, each drawn as a cube of six 90
+// degree views into consecutive layers of a depth texture array. The shader
+// picks the face from the direction to the light itself, so it doesn't need
+// cube array support.
+pub struct PointShadowMap {
+    view: wgpu::TextureView,
+    uniform_buf: wgpu::Buffer,
+    faces: Vec<ShadowLayer>,
+    count: usize,
+}
+
+impl PointShadowMap {
+    pub fn new(
+        device: &wgpu::Device,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        settings: &ShadowSettings,
+        lights: usize,
+    ) -> Self {
+        let lights = lights.clamp(1, SHADOWED_POINT_LIGHTS);
+        let texture = texture::create_depth_texture(
+            device,
+            "point shadow map",
+            settings.point_resolution,
+            settings.point_resolution,
+            6 * lights as u32,
+        );
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let uniform_buf = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("point shadow uniform"),
+            size: size_of::<PointShadowUniformData>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let faces = (0..6 * lights as u32)
+            .map(|layer| ShadowLayer::new(device, camera_bind_group_layout, &texture, layer))
+            .collect();
+        Self {
+            view,
+            uniform_buf,
+            faces,
+            count: 0,
+        }
+    }
+
+    // Every face of every light, for sampling.
+    pub fn view(&self) -> &wgpu::TextureView {
+        &self.view
+    }
+
+    pub fn uniform_buf(&self) -> &wgpu::Buffer {
+        &self.uniform_buf
+    }
+
+    // How many lights can have a shadow.
+    pub fn capacity(&self) -> usize {
+        self.faces.len() / 6
+    }
+
+    // The six faces of each light with a shadow, in the lights' order.
+    pub fn lights(&self) -> impl Iterator<Item = &[ShadowLayer]> {
+        self.faces[..6 * self.count].chunks(6)
+    }
+
+    pub fn update(
+        &mut self,
+        queue: &wgpu::Queue,
+        lights: &[PointLight],
+        camera_position: Vector3<f32>,
+    ) {
+        self.count = lights.len().min(self.capacity());
+        let mut uniform = PointShadowUniformData {
+            view_projections: [[[0.0; 4]; 4]; 6 * SHADOWED_POINT_LIGHTS],
+            count: self.count as u32,
+            _padding: [0; 3],
+        };
+        for (i, light) in lights[..self.count].iter().enumerate() {
+            let proj = OPENGL_TO_WGPU_MATRIX * perspective(Deg(90.0), 1.0, 0.01, light.radius);
+            for (j, (direction, up)) in CUBE_FACES.into_iter().enumerate() {
+                let view = Matrix4::look_to_rh(Point3::from_vec(light.position), direction, up);
+                self.faces[6 * i + j].write(queue, view, proj, camera_position);
+                uniform.view_projections[6 * i + j] = (proj * view).into();
+            }
+        }
+        queue.write_buffer(&self.uniform_buf, 0, bytemuck::bytes_of(&uniform));
+    }
+}
+
+impl ShadowSettings {
+    // The view space depth at which each cascade ends, the last one at the
+    // shadow distance.
+    fn splits(&self, near: f32) -> [f32; CASCADES] {
+        let far = self.distance;
+        std::array::from_fn(|i| {
+            let t = (i + 1) as f32 / CASCADES as f32;
+            let log = near * (far / near).powf(t);
+            let even = near + (far - near) * t;
+            even + (log - even) * self.split_lambda
+        })
+    }
+
+    // The sun's view and orthographic projection around the part of the
+    // camera's view between two depths, and the world size of one texel.
+    fn fit(
+        &self,
+        camera: &CameraWrapper,
+        aspect: f32,
+        near: f32,
+        far: f32,
+        direction: Vector3<f32>,
+    ) -> (Matrix4<f32>, Matrix4<f32>, f32) {
+        let inverse = (camera.get_projection_matrix(aspect, near, far) * camera.get_view_matrix())
+            .invert()
+            .unwrap_or(Matrix4::identity());
+        let corners = [-1.0, 1.0]
+            .into_iter()
+            .flat_map(|x| [-1.0, 1.0].map(|y| (x, y)))
+            .flat_map(|(x, y)| [0.0, 1.0].map(|z| point3(x, y, z)))
+            .map(|p| inverse.transform_point(p))
+            .collect::<Vec<_>>();
+        let centre = Point3::centroid(&corners);
+        let radius = corners
+            .iter()
+            .map(|c| (c - centre).magnitude())
+            .fold(0.0, f32::max);
+        // Rounded up so the size, and with it the texel size, doesn't flicker.
+        let radius = (radius * 16.0).ceil() / 16.0;
+        let texel_size = 2.0 * radius / self.resolution as f32;
+
+        let direction = direction.normalize();
+        let up = if direction.y.abs() > 0.99 {
+            Vector3::unit_z()
+        } else {
+            Vector3::unit_y()
+        };
+        let view = Matrix4::look_to_rh(Point3::origin(), direction, up);
+        let centre = view.transform_point(centre);
+        let (x, y) = (
+            (centre.x / texel_size).floor() * texel_size,
+            (centre.y / texel_size).floor() * texel_size,
+        );
+        let proj = OPENGL_TO_WGPU_MATRIX
+            * ortho(
+                x - radius,
+                x + radius,
+                y - radius,
+                y + radius,
+                -centre.z - radius - CASTER_MARGIN,
+                -centre.z + radius,
+            );
+        (view, proj, texel_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_run_from_even_to_logarithmic() {
+        let even = ShadowSettings {
+            split_lambda: 0.0,
+            distance: 40.0,
+            ..Default::default()
+        };
+        assert_eq!(even.splits(4.0), [13.0, 22.0, 31.0, 40.0]);
+
+        let log = ShadowSettings {
+            split_lambda: 1.0,
+            distance: 1000.0,
+            ..Default::default()
+        };
+        for (split, expected) in log.splits(1.0).iter().zip([5.623, 31.62, 177.8, 1000.0]) {
+            assert!(
+                (split - expected).abs() < expected * 1e-3,
+                "{split} != {expected}"
+            );
+        }
+
+        let splits = ShadowSettings::default().splits(0.1);
+        assert!(splits.windows(2).all(|w| w[0] < w[1]));
+        assert!((splits[CASCADES - 1] - ShadowSettings::default().distance).abs() < 1e-4);
+    }
+
+    #[test]
+    fn cascades_contain_their_part_of_the_view() {
+        let settings = ShadowSettings::default();
+        let camera = CameraWrapper::default();
+        let (aspect, near, far) = (1.5, 2.0, 8.0);
+        let view_projection =
+            camera.get_projection_matrix(aspect, near, far) * camera.get_view_matrix();
+        let inverse = view_projection.invert().unwrap();
+        for direction in [
+            vec3(0.3, -1.0, 0.2),
+            vec3(1.0, -0.1, 0.0),
+            vec3(0.0, -1.0, 0.0),
+        ] {
+            let (view, proj, texel_size) = settings.fit(&camera, aspect, near, far, direction);
+            let sun = proj * view;
+            for x in [-1.0, 1.0] {
+                for y in [-1.0, 1.0] {
+                    for z in [0.0, 1.0] {
+                        let p = sun.transform_point(inverse.transform_point(point3(x, y, z)));
+                        assert!(p.x.abs() <= 1.0 && p.y.abs() <= 1.0, "{p:?}");
+                        assert!((0.0..=1.0).contains(&p.z), "{p:?}");
+                    }
+                }
+            }
+            // Only the size of the piece of view sets the texel size, so it
+            // doesn't change as the sun moves.
+            assert_eq!(
+                texel_size,
+                settings
+                    .fit(&camera, aspect, near, far, vec3(0.0, -1.0, 1.0))
+                    .2
+            );
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::*;
+use cgmath::*;
+
+use crate::entity::Entity;
+
+struct Triangle {
+    corners: [Vector3<f32>; 3],
+    color: [f32; 4],
+}
+
+impl Triangle {
+    fn normal(&self) -> Vector3<f32> {
+        let [a, b, c] = self.corners;
+        let n = (b - a).cross(c - a);
+        if n.magnitude2() > 0.0 {
+            n.normalize()
+        } else {
+            n
+        }
+    }
+
+    // Flips the winding when the triangle faces away from `outward`.
+    fn facing(mut self, outward: Vector3<f32>) -> Self {
+        if self.normal().dot(outward) < 0.0 {
+            self.corners.swap(1, 2);
+        }
+        self
+    }
+}
+
+// Binary STL in world space, turned z up since that is what slicers expect.
+// With a skirt, every open edge of the mesh is extended down to a flat base
+// `skirt` below the lowest vertex and the base is capped, so a terrain tile
+// prints as a closed solid. Facet colors use the VisCAM/SolidView attribute bits.
+pub fn save(path: &Path, entity: &Entity, skirt: Option<f32>) -> Result<()> {
+    let model = entity.get_model_matrix();
+    let mesh = entity.mesh();
+    let positions: Vec<Vector3<f32>> = mesh
+        .vertices()
+        .iter()
+        .map(|v| (model * Vector4::from(v.pos)).truncate())
+        .collect();
+    let mut triangles: Vec<Triangle> = mesh
+        .indices()
+        .chunks_exact(3)
+        .map(|t| {
+            let corners = [t[0], t[1], t[2]].map(|i| positions[i as usize]);
+            let color = [t[0], t[1], t[2]]
+                .map(|i| Vector4::from(mesh.vertices()[i as usize].color))
+                .iter()
+                .sum::<Vector4<f32>>()
+                / 3.0;
+            Triangle {
+                corners,
+                color: color.into(),
+            }
+        })
+        .collect();
+    if let Some(depth) = skirt {
+        let walls = skirt_triangles(&positions, mesh.indices(), depth);
+        triangles.extend(walls);
+    }
+
+    let mut out = BufWriter::new(File::create(path)?);
+    let mut header = [0u8; 80];
+    let title = b"terrain-gen";
+    header[..title.len()].copy_from_slice(title);
+    out.write_all(&header)?;
+    out.write_all(&(triangles.len() as u32).to_le_bytes())?;
+    let z_up = |v: Vector3<f32>| [v.x, -v.z, v.y];
+    for triangle in &triangles {
+        for value in z_up(triangle.normal()) {
+            out.write_all(&value.to_le_bytes())?;
+        }
+        for corner in triangle.corners {
+            for value in z_up(corner) {
+                out.write_all(&value.to_le_bytes())?;
+            }
+        }
+        let [r, g, b] =
+            [0, 1, 2].map(|c| (triangle.color[c].clamp(0.0, 1.0) * 31.0).round() as u16);
+        out.write_all(&(0x8000 | r << 10 | g << 5 | b).to_le_bytes())?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// Open edges are the ones only one triangle uses. The base is fanned from the
+// middle of those edges, which closes any tile whose outline is convex.
+fn skirt_triangles(positions: &[Vector3<f32>], indices: &[u32], depth: f32) -> Vec<Triangle> {
+    let mut edges: HashMap<(u32, u32), u32> = HashMap::new();
+    for t in indices.chunks_exact(3) {
+        for (a, b) in [(t[0], t[1]), (t[1], t[2]), (t[2], t[0])] {
+            *edges.entry((a.min(b), a.max(b))).or_default() += 1;
+        }
+    }
+    let open_edges: Vec<(u32, u32)> = edges
+        .into_iter()
+        .filter(|&(_, count)| count == 1)
+        .map(|(edge, _)| edge)
+        .collect();
+    if open_edges.is_empty() {
+        return vec![];
+    }
+
+    let base = positions.iter().map(|p| p.y).fold(f32::MAX, f32::min) - depth;
+    let down = |p: Vector3<f32>| vec3(p.x, base, p.z);
+    let centre = open_edges
+        .iter()
+        .map(|&(a, _)| down(positions[a as usize]))
+        .sum::<Vector3<f32>>()
+        / open_edges.len() as f32;
+    let color = [0.5, 0.5, 0.5, 1.0];
+
+    let mut triangles = vec![];
+    for (a, b) in open_edges {
+        let (a, b) = (positions[a as usize], positions[b as usize]);
+        let (a_base, b_base) = (down(a), down(b));
+        let outward = ((a_base + b_base) * 0.5 - centre).normalize();
+        triangles.push(
+            Triangle {
+                corners: [a, b, b_base],
+                color,
+            }
+            .facing(outward),
+        );
+        triangles.push(
+            Triangle {
+                corners: [a, b_base, a_base],
+                color,
+            }
+            .facing(outward),
+        );
+        triangles.push(
+            Triangle {
+                corners: [centre, a_base, b_base],
+                color,
+            }
+            .facing(-Vector3::unit_y()),
+        );
+    }
+    triangles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{heightfield::HeightFunction, vertex::Mesh};
+
+    struct Bumps;
+
+    impl HeightFunction for Bumps {
+        fn sample(&self, u: f32, v: f32) -> f32 {
+            (u * 7.0).sin() * (v * 5.0).cos() * 0.2
+        }
+    }
+
+    #[test]
+    fn skirt_closes_the_terrain() {
+        let mut mesh = Mesh::new(vec![], vec![]);
+        mesh.generate_plane(4, &Bumps);
+        // Heights along y, like the terrain entity.
+        mesh.transform(Matrix4::from_angle_x(Deg(-90.0)));
+        let positions: Vec<Vector3<f32>> = mesh
+            .vertices()
+            .iter()
+            .map(|v| Vector4::from(v.pos).truncate())
+            .collect();
+        let corners = mesh
+            .indices()
+            .chunks_exact(3)
+            .map(|t| [t[0], t[1], t[2]].map(|i| positions[i as usize]))
+            .chain(
+                skirt_triangles(&positions, mesh.indices(), 0.1)
+                    .into_iter()
+                    .map(|t| t.corners),
+            );
+
+        // Closed when every edge is shared by exactly two triangles.
+        let key = |p: Vector3<f32>| [p.x, p.y, p.z].map(f32::to_bits);
+        let mut edges: HashMap<_, u32> = HashMap::new();
+        for [a, b, c] in corners {
+            for (p, q) in [(a, b), (b, c), (c, a)] {
+                let (p, q) = (key(p), key(q));
+                *edges.entry((p.min(q), p.max(q))).or_default() += 1;
+            }
+        }
+        assert!(edges.values().all(|&count| count == 2));
+    }
+}
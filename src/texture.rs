@@ -1,9 +1,21 @@
 use anyhow::*;
 use image::GenericImageView;
+
+use crate::heightfield::Heightfield;
+
 pub struct Texture {
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
     pub sampler: wgpu::Sampler,
+    pub source: Option<TextureSource>,
+}
+
+// The encoded file a texture was loaded from, kept so exporters can write it
+// next to the model that references it.
+#[derive(Clone)]
+pub struct TextureSource {
+    pub name: String,
+    pub bytes: Vec<u8>,
 }
 
 impl Texture {
@@ -15,7 +27,81 @@ impl Texture {
         texture_format: wgpu::TextureFormat,
     ) -> Result<Self> {
         let img = image::load_from_memory(bytes)?;
-        Self::from_image(device, queue, &img, Some(label), texture_format)
+        let mut texture = Self::from_image(device, queue, &img, Some(label), texture_format)?;
+        texture.source = Some(TextureSource {
+            name: String::from(label),
+            bytes: bytes.to_vec(),
+        });
+        Ok(texture)
+    }
+
+    pub fn from_color(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        color: [u8; 4],
+        label: &str,
+        texture_format: wgpu::TextureFormat,
+    ) -> Result<Self> {
+        let img = image::RgbaImage::from_pixel(1, 1, image::Rgba(color));
+        Self::from_image(device, queue, &img.into(), Some(label), texture_format)
+    }
+
+    pub fn from_heightfield(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        heightfield: &Heightfield,
+        label: &str,
+    ) -> Self {
+        let size = wgpu::Extent3d {
+            width: heightfield.width(),
+            height: heightfield.height(),
+            depth_or_array_layers: 1,
+        };
+        let texture = device.create_texture(&wgpu::wgt::TextureDescriptor {
+            label: Some(label),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::STORAGE_BINDING
+                | wgpu::TextureUsages::COPY_DST
+                | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+
+        queue.write_texture(
+            wgpu::TexelCopyTextureInfoBase {
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            bytemuck::cast_slice(heightfield.data()),
+            wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * size.width),
+                rows_per_image: Some(size.height),
+            },
+            size,
+        );
+        let view = texture.create_view(&wgpu::wgt::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::wgt::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        Self {
+            texture,
+            view,
+            sampler,
+            source: None,
+        }
     }
 
     fn from_image(
@@ -72,6 +158,32 @@ impl Texture {
             texture,
             view,
             sampler,
+            source: None,
         })
     }
 }
+
+// A Depth32Float texture that can be drawn into and then sampled, with one
+// layer per shadow cascade where that's what it's for.
+pub fn create_depth_texture(
+    device: &wgpu::Device,
+    label: &str,
+    width: u32,
+    height: u32,
+    layers: u32,
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::wgt::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: layers,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Depth32Float,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    })
+}
@@ -1,8 +1,10 @@
+use std::ops::Range;
+
 use bytemuck::{Pod, Zeroable};
 use cgmath::*;
 use wgpu::{util::DeviceExt, vertex_attr_array};
 
-use crate::texture;
+use crate::{frustum::Aabb, heightfield::HeightFunction, texture};
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
@@ -26,6 +28,7 @@ impl Vertex {
     };
 }
 
+#[derive(Clone)]
 pub struct Mesh {
     vertices: Vec<Vertex>,
     indices: Vec<u32>,
@@ -35,6 +38,41 @@ impl Mesh {
         Self { vertices, indices }
     }
 
+    pub fn vertices(&self) -> &[Vertex] {
+        &self.vertices
+    }
+
+    pub fn indices(&self) -> &[u32] {
+        &self.indices
+    }
+
+    // Moves the vertices into the space `matrix` maps to. Normals go through
+    // the inverse transpose so they stay perpendicular under non-uniform
+    // scale, and mirroring transforms flip the winding back to front facing.
+    pub fn transform(&mut self, matrix: Matrix4<f32>) {
+        let normal_matrix = normal_matrix(matrix);
+        for vertex in self.vertices.iter_mut() {
+            vertex.pos = (matrix * Vector4::from(vertex.pos)).into();
+            let normal = normal_matrix * Vector4::from(vertex.normal).truncate();
+            if normal.magnitude2() > 0.0 {
+                vertex.normal = normal.normalize().extend(1.0).into();
+            }
+        }
+        if matrix.determinant() < 0.0 {
+            for triangle in self.indices.chunks_exact_mut(3) {
+                triangle.swap(1, 2);
+            }
+        }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        Aabb::from_points(
+            self.vertices
+                .iter()
+                .map(|v| Point3::from_homogeneous(v.pos.into())),
+        )
+    }
+
     pub fn to_mesh_data(&self, device: &wgpu::Device) -> MeshData {
         let vertex_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: None,
@@ -55,7 +93,7 @@ impl Mesh {
         }
     }
 
-    pub fn generate_plane(&mut self, resolution: u32) {
+    pub fn generate_plane(&mut self, resolution: u32, terrain: &(impl HeightFunction + ?Sized)) {
         let resolution_recip = 1.0 / resolution as f32;
         let vertices: Vec<cgmath::Point3<f32>> = (0..(resolution + 1))
             .flat_map(|x| {
@@ -81,11 +119,15 @@ impl Mesh {
             .collect();
         let vertices_raw: Vec<Vertex> = vertices
             .into_iter()
-            .map(|v| Vertex {
-                pos: v.to_homogeneous().into(),
-                color: [1.0, 1.0, 1.0, 1.0],
-                normal: [0.0, 0.0, 1.0, 1.0],
-                tex_coords: v.xy().into(),
+            .map(|v| {
+                let pos = point3(v.x, v.y, terrain.sample(v.x, v.y));
+                let normal = terrain.normal(v.x, v.y);
+                Vertex {
+                    pos: pos.to_homogeneous().into(),
+                    color: [1.0, 1.0, 1.0, 1.0],
+                    normal: normal.extend(1.0).into(),
+                    tex_coords: v.xy().into(),
+                }
             })
             .collect();
         self.vertices.extend(vertices_raw.iter());
@@ -253,6 +295,146 @@ impl Mesh {
     }
 }
 
+// Sides of a generate_plane grid: u = 0, u = 1, v = 0 and v = 1.
+#[derive(Debug, Clone, Copy)]
+pub enum PlaneEdge {
+    Left,
+    Right,
+    Bottom,
+    Top,
+}
+
+impl PlaneEdge {
+    pub const ALL: [PlaneEdge; 4] = [Self::Left, Self::Right, Self::Bottom, Self::Top];
+
+    // Grid point `t` along the edge and `depth` rows in from it.
+    fn point(self, resolution: u32, t: u32, depth: u32) -> (u32, u32) {
+        match self {
+            Self::Left => (depth, t),
+            Self::Right => (resolution - depth, t),
+            Self::Bottom => (t, depth),
+            Self::Top => (t, resolution - depth),
+        }
+    }
+}
+
+// Another way to index a generate_plane grid, for patches drawn next to
+// coarser ones: the interior quads, plus a strip along each edge for every
+// neighbour step. The strip for step s joins the first inner row to every
+// s-th vertex of the edge, which are the only edge vertices a neighbour with
+// s times fewer quads has, so the two meet without T-junction cracks.
+// Everything lives in one index list; the ranges pick the pieces out of it.
+pub struct PlaneStitching {
+    pub indices: Vec<u32>,
+    pub interior: Range<u32>,
+    edges: [Vec<Range<u32>>; 4],
+}
+
+impl PlaneStitching {
+    // Builds strips for steps 1, 2, 4, ... up to 2^(steps - 1), as far as
+    // they divide the resolution.
+    pub fn new(resolution: u32, steps: u32) -> Self {
+        let index = |u: u32, v: u32| u * (resolution + 1) + v;
+        let mut indices = vec![];
+        let mut edges: [Vec<Range<u32>>; 4] = Default::default();
+        if resolution < 2 {
+            // No inner row to stitch to, so the whole grid is the interior.
+            for u in 0..resolution {
+                for v in 0..resolution {
+                    indices.extend([index(u, v), index(u + 1, v), index(u, v + 1)]);
+                    indices.extend([index(u + 1, v), index(u + 1, v + 1), index(u, v + 1)]);
+                }
+            }
+            let interior = 0..indices.len() as u32;
+            return Self {
+                indices,
+                interior,
+                edges,
+            };
+        }
+
+        for u in 1..resolution - 1 {
+            for v in 1..resolution - 1 {
+                indices.extend([index(u, v), index(u + 1, v), index(u, v + 1)]);
+                indices.extend([index(u + 1, v), index(u + 1, v + 1), index(u, v + 1)]);
+            }
+        }
+        let interior = 0..indices.len() as u32;
+
+        for edge in PlaneEdge::ALL {
+            for step in (0..steps).map(|k| 1 << k) {
+                if step > resolution || !resolution.is_multiple_of(step) {
+                    break;
+                }
+                let start = indices.len() as u32;
+                let outer_row: Vec<u32> = (0..=resolution).step_by(step as usize).collect();
+                let inner_row: Vec<u32> = (1..resolution).collect();
+                // Zip the two rows together, always advancing whichever row's
+                // next vertex comes first along the edge.
+                let (mut i, mut j) = (0, 0);
+                while i + 1 < outer_row.len() || j + 1 < inner_row.len() {
+                    let advance_outer = j + 1 == inner_row.len()
+                        || (i + 1 < outer_row.len() && outer_row[i + 1] <= inner_row[j + 1]);
+                    let triangle = if advance_outer {
+                        i += 1;
+                        [
+                            edge.point(resolution, outer_row[i - 1], 0),
+                            edge.point(resolution, outer_row[i], 0),
+                            edge.point(resolution, inner_row[j], 1),
+                        ]
+                    } else {
+                        j += 1;
+                        [
+                            edge.point(resolution, outer_row[i], 0),
+                            edge.point(resolution, inner_row[j], 1),
+                            edge.point(resolution, inner_row[j - 1], 1),
+                        ]
+                    };
+                    indices.extend(counter_clockwise(triangle).map(|(u, v)| index(u, v)));
+                }
+                edges[edge as usize].push(start..indices.len() as u32);
+            }
+        }
+
+        Self {
+            indices,
+            interior,
+            edges,
+        }
+    }
+
+    // The strip along `edge` for a neighbour 2^step_level times coarser,
+    // falling back to the coarsest strip there is.
+    pub fn edge(&self, edge: PlaneEdge, step_level: usize) -> Range<u32> {
+        let strips = &self.edges[edge as usize];
+        strips
+            .get(step_level)
+            .or(strips.last())
+            .cloned()
+            .unwrap_or(0..0)
+    }
+}
+
+// Winds a triangle of grid points the same way generate_plane does.
+pub fn counter_clockwise(mut triangle: [(u32, u32); 3]) -> [(u32, u32); 3] {
+    let [a, b, c] = triangle.map(|(u, v)| (u as i64, v as i64));
+    if (b.0 - a.0) * (c.1 - a.1) - (b.1 - a.1) * (c.0 - a.0) < 0 {
+        triangle.swap(1, 2);
+    }
+    triangle
+}
+
+// The inverse transpose of the matrix's linear part, which takes normals along
+// with it. It doesn't keep their length, so the results need normalizing.
+pub fn normal_matrix(matrix: Matrix4<f32>) -> Matrix3<f32> {
+    let linear = Matrix3::from_cols(
+        matrix.x.truncate(),
+        matrix.y.truncate(),
+        matrix.z.truncate(),
+    );
+    linear.invert().unwrap_or(linear).transpose()
+}
+
 pub struct MeshData {
     pub vertex_buf: wgpu::Buffer,
     pub index_buf: wgpu::Buffer,
@@ -304,3 +486,119 @@ impl Material {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vertex(pos: [f32; 3], normal: [f32; 3]) -> Vertex {
+        Vertex {
+            pos: [pos[0], pos[1], pos[2], 1.0],
+            color: [1.0; 4],
+            normal: [normal[0], normal[1], normal[2], 1.0],
+            tex_coords: [0.0; 2],
+        }
+    }
+
+    fn triangle() -> Mesh {
+        let normal = vec3(1.0f32, 0.0, 1.0).normalize().into();
+        Mesh::new(
+            vec![
+                vertex([0.0, 0.0, 0.0], normal),
+                vertex([1.0, 0.0, -1.0], normal),
+                vertex([0.0, 1.0, 0.0], normal),
+            ],
+            vec![0, 1, 2],
+        )
+    }
+
+    fn position(mesh: &Mesh, i: usize) -> Vector3<f32> {
+        Vector4::from(mesh.vertices()[i].pos).truncate()
+    }
+
+    #[test]
+    fn transform_keeps_normals_perpendicular() {
+        let mut mesh = triangle();
+        let matrix = Matrix4::from_translation(vec3(5.0, 0.0, 0.0))
+            * Matrix4::from_angle_y(Deg(30.0))
+            * Matrix4::from_nonuniform_scale(3.0, 1.0, 0.5);
+        mesh.transform(matrix);
+        assert!((position(&mesh, 0) - vec3(5.0, 0.0, 0.0)).magnitude() < 1e-5);
+        let normal = Vector4::from(mesh.vertices()[0].normal).truncate();
+        assert!((normal.magnitude() - 1.0).abs() < 1e-5);
+        for edge in [
+            position(&mesh, 1) - position(&mesh, 0),
+            position(&mesh, 2) - position(&mesh, 0),
+        ] {
+            assert!(normal.dot(edge).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn mirroring_keeps_triangles_front_facing() {
+        let mut mesh = triangle();
+        mesh.transform(Matrix4::from_nonuniform_scale(-1.0, 1.0, 1.0));
+        let face = (position(&mesh, mesh.indices()[1] as usize) - position(&mesh, 0))
+            .cross(position(&mesh, mesh.indices()[2] as usize) - position(&mesh, 0));
+        let normal = Vector4::from(mesh.vertices()[0].normal).truncate();
+        assert!(face.dot(normal) > 0.0);
+    }
+
+    // The triangles in a range of the stitching's indices, as grid points.
+    fn triangles(
+        stitching: &PlaneStitching,
+        resolution: u32,
+        range: Range<u32>,
+    ) -> Vec<[(i64, i64); 3]> {
+        stitching.indices[range.start as usize..range.end as usize]
+            .chunks_exact(3)
+            .map(|t| {
+                [t[0], t[1], t[2]]
+                    .map(|i| ((i / (resolution + 1)) as i64, (i % (resolution + 1)) as i64))
+            })
+            .collect()
+    }
+
+    fn doubled_area([a, b, c]: [(i64, i64); 3]) -> i64 {
+        (b.0 - a.0) * (c.1 - a.1) - (b.1 - a.1) * (c.0 - a.0)
+    }
+
+    #[test]
+    fn stitched_edges_cover_the_plane() {
+        let resolution = 8;
+        let stitching = PlaneStitching::new(resolution, 3);
+        for step_level in 0..3 {
+            let mut area = 0;
+            let ranges = std::iter::once(stitching.interior.clone())
+                .chain(PlaneEdge::ALL.map(|edge| stitching.edge(edge, step_level)));
+            for range in ranges {
+                for triangle in triangles(&stitching, resolution, range) {
+                    // Front facing and never degenerate.
+                    assert!(doubled_area(triangle) > 0);
+                    area += doubled_area(triangle);
+                }
+            }
+            assert_eq!(area, 2 * (resolution * resolution) as i64);
+        }
+    }
+
+    #[test]
+    fn stitched_edges_only_use_the_coarser_neighbours_vertices() {
+        let resolution = 8;
+        let stitching = PlaneStitching::new(resolution, 3);
+        for step_level in 0..3 {
+            let step = 1 << step_level;
+            let range = stitching.edge(PlaneEdge::Left, step_level);
+            for triangle in triangles(&stitching, resolution, range) {
+                for (u, v) in triangle {
+                    assert!(u == 1 || v % step == 0, "({u}, {v}) at step {step}");
+                }
+            }
+        }
+        // Neighbours coarser than the strips built for use the coarsest one.
+        assert_eq!(
+            stitching.edge(PlaneEdge::Top, 5),
+            stitching.edge(PlaneEdge::Top, 2)
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+    },
+    thread::JoinHandle,
+};
+
+use crate::{
+    chunk::{ChunkCoord, ChunkGenerator},
+    vertex::Mesh,
+};
+
+struct Job {
+    coord: ChunkCoord,
+    cancelled: Arc<AtomicBool>,
+}
+
+// Builds chunk meshes on background threads so the event loop only pays for
+// the GPU upload. Workers share one job queue and report back on another.
+pub struct ChunkWorkers {
+    jobs: Option<Sender<Job>>,
+    finished: Receiver<(ChunkCoord, Vec<Mesh>)>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl ChunkWorkers {
+    pub fn new(generator: Arc<ChunkGenerator>) -> Self {
+        let (jobs, job_queue) = mpsc::channel::<Job>();
+        let (finished_sender, finished) = mpsc::channel();
+        let job_queue = Arc::new(Mutex::new(job_queue));
+        let count = std::thread::available_parallelism()
+            .map(|n| n.get().saturating_sub(1))
+            .unwrap_or(1)
+            .max(1);
+        let threads = (0..count)
+            .map(|i| {
+                let generator = generator.clone();
+                let job_queue = job_queue.clone();
+                let finished = finished_sender.clone();
+                std::thread::Builder::new()
+                    .name(format!("chunk worker {i}"))
+                    .spawn(move || {
+                        loop {
+                            // The lock is only held while waiting for a job.
+                            let job = job_queue.lock().unwrap().recv();
+                            let Ok(job) = job else {
+                                break;
+                            };
+                            if job.cancelled.load(Ordering::Relaxed) {
+                                continue;
+                            }
+                            if let Some(meshes) = generator.generate(job.coord, &job.cancelled)
+                                && finished.send((job.coord, meshes)).is_err()
+                            {
+                                break;
+                            }
+                        }
+                    })
+                    .expect("unable to spawn chunk worker")
+            })
+            .collect();
+        Self {
+            jobs: Some(jobs),
+            finished,
+            threads,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.threads.len()
+    }
+
+    pub fn queue(&self, coord: ChunkCoord, cancelled: Arc<AtomicBool>) {
+        if let Some(jobs) = &self.jobs {
+            let _ = jobs.send(Job { coord, cancelled });
+        }
+    }
+
+    pub fn finished(&self, max: usize) -> Vec<(ChunkCoord, Vec<Mesh>)> {
+        self.finished.try_iter().take(max).collect()
+    }
+}
+
+impl Drop for ChunkWorkers {
+    fn drop(&mut self) {
+        // Closing the queue lets every worker fall out of its loop once the
+        // jobs already queued are gone. ChunkStreamer cancels those first, so
+        // this doesn't wait on chunks nobody will use.
+        self.jobs = None;
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chunk::ChunkSettings,
+        erosion::{HydraulicErosion, ThermalErosion},
+        fractal::TerrainPreset,
+        noise::{Noise, NoiseKind},
+    };
+
+    #[test]
+    fn cancelled_jobs_are_skipped() {
+        let workers = ChunkWorkers::new(Arc::new(ChunkGenerator {
+            terrain: TerrainPreset::Hills
+                .build(Noise::new(1), NoiseKind::Simplex)
+                .into(),
+            seed: 1,
+            hydraulic_erosion: HydraulicErosion::default(),
+            thermal_erosion: ThermalErosion::default(),
+            settings: ChunkSettings {
+                resolution: 8,
+                ..Default::default()
+            },
+        }));
+        let cancelled = Arc::new(AtomicBool::new(true));
+        for x in 0..16 {
+            workers.queue(ChunkCoord { x, y: 0 }, cancelled.clone());
+        }
+        let wanted = ChunkCoord { x: 0, y: 1 };
+        workers.queue(wanted, Arc::new(AtomicBool::new(false)));
+
+        let (coord, meshes) = workers.finished.recv().unwrap();
+        assert_eq!(coord, wanted);
+        assert!(!meshes.is_empty());
+        assert!(workers.finished(usize::MAX).is_empty());
+    }
+}